@@ -4,7 +4,10 @@ use std::{
     path::Path,
 };
 
-use crate::{dpt_file::DptFile, store::get_dpt_dir};
+use crate::{
+    dpt_file::DptFile,
+    store::{get_dpt_dir, is_rootless},
+};
 use anyhow::Result;
 
 fn mkdir_p(d: &Path) -> Result<()> {
@@ -53,7 +56,8 @@ fn build_directory_structure(base_dir: &Path) -> Result<()> {
 }
 
 fn hard_links(base_dir: &Path) -> Result<()> {
-    if Path::new("/etc/fstab").exists() {
+    // Users can't hard link files they don't own
+    if Path::new("/etc/fstab").exists() && !is_rootless() {
         hard_link("/etc/fstab", base_dir.join("etc/fstab"))?;
     }
     Ok(())
@@ -0,0 +1,363 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+
+use crate::{
+    dpt_file::read_dpt_lock_file,
+    index::write_atomically,
+    pkg::Package,
+    repo::{package_to_onlinepackage, OnlinePackage},
+    run::join_proper,
+    store::{get_dpt_dir, get_invoking_uid},
+    user_profile::{get_profile_dir, read_profile_lock},
+};
+
+/// Directories of a package that binaries are looked up in, by `dpt run` too
+pub const BIN_DIRS: [&str; 2] = ["usr/bin", "bin"];
+
+/// The packages that provide each binary, in the order of `dpt.lock`
+pub type BinIndex = BTreeMap<String, Vec<Package>>;
+
+pub fn get_bin_index_location() -> PathBuf {
+    get_dpt_dir().join("bin-index.ron")
+}
+
+/// Lists the binaries that a package provides
+fn package_bins(pkg: &OnlinePackage) -> Result<Vec<String>> {
+    let root = Path::new(&pkg.url);
+    let mut ret = Vec::<String>::new();
+    for dir in BIN_DIRS {
+        let Ok(entries) = std::fs::read_dir(root.join(dir)) else {
+            continue;
+        };
+        for ent in entries {
+            let name = ent?.file_name().to_string_lossy().to_string();
+            if !ret.contains(&name)
+                && join_proper(&root.join(dir), Path::new(&name))?.is_file()
+            {
+                ret.push(name);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Indexes the binaries of `pkgs`, which are looked up in `installed`
+pub fn build_bin_index(
+    pkgs: &[Package],
+    installed: &Vec<OnlinePackage>,
+) -> Result<BinIndex> {
+    let mut index = BinIndex::new();
+    for pkg in pkgs {
+        let online = package_to_onlinepackage(pkg, installed)?;
+        for bin in package_bins(&online)? {
+            index.entry(bin).or_default().push(pkg.clone());
+        }
+    }
+    Ok(index)
+}
+
+pub fn write_bin_index(index: &BinIndex, path: &Path) -> Result<()> {
+    write_atomically(
+        path,
+        ron::ser::to_string_pretty(index, ron::ser::PrettyConfig::default())?
+            .as_bytes(),
+    )
+    .context("Failed to write the binary index")
+}
+
+pub fn read_bin_index(path: &Path) -> Result<BinIndex> {
+    Ok(ron::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+/// Where `dpt rebuild` puts a launcher for every binary, for the host's `PATH`
+pub fn get_launcher_dir() -> PathBuf {
+    get_dpt_dir().join("bin")
+}
+
+/// Whether a file is a launcher, which is a hard link of `dpt`. Regular files
+/// with other links are left by an older `dpt`.
+fn is_launcher(file: &std::fs::Metadata, dpt: &std::fs::Metadata) -> bool {
+    (file.dev() == dpt.dev() && file.ino() == dpt.ino())
+        || (file.is_file() && file.nlink() > 1)
+}
+
+/// Makes the launcher directory `dir` hold a hard link of `dpt` for every
+/// binary in `index`, which runs the binary by the name it is called as.
+/// Other launchers in the directory are removed, and anything that isn't a
+/// launcher is warned about and left alone.
+pub fn update_launchers(
+    index: &BinIndex,
+    dpt: &Path,
+    dir: &Path,
+) -> Result<()> {
+    std::fs::DirBuilder::new().recursive(true).create(dir)?;
+    let me = std::fs::metadata(dpt)?;
+
+    for ent in std::fs::read_dir(dir)? {
+        let path = ent?.path();
+        let name = path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if index.contains_key(&name) && name != "dpt" {
+            continue;
+        }
+        if !is_launcher(&std::fs::symlink_metadata(&path)?, &me) {
+            warn!("{} isn't a launcher, leaving it alone", path.display());
+            continue;
+        }
+        std::fs::remove_file(&path).context(anyhow!(
+            "Failed to remove stale launcher {}",
+            path.display()
+        ))?;
+    }
+
+    for bin in index.keys() {
+        if bin == "dpt" {
+            // It would run dpt itself
+            warn!("A package provides a binary called dpt, which gets no launcher");
+            continue;
+        }
+        let link = dir.join(bin);
+        match std::fs::symlink_metadata(&link) {
+            Ok(x) if x.dev() == me.dev() && x.ino() == me.ino() => continue,
+            // E.g. a link of an older dpt
+            Ok(x) if is_launcher(&x, &me) => std::fs::remove_file(&link)
+                .context(anyhow!(
+                    "Failed to replace launcher {}",
+                    link.display()
+                ))?,
+            Ok(_) => bail!(
+                "Can't create the launcher for {bin}, {} is in the way and isn't a launcher!",
+                link.display()
+            ),
+            Err(_) => {}
+        }
+        std::fs::hard_link(dpt, &link)
+            .context(anyhow!("Failed to create launcher {}", link.display()))?;
+    }
+    Ok(())
+}
+
+/// Lists the binaries in `index` that are also commands in another directory
+/// of `PATH` than `launchers`, which either hide the launchers or are hidden
+/// by them
+pub fn path_clashes<'a>(
+    index: &'a BinIndex,
+    launchers: &Path,
+) -> Vec<&'a String> {
+    let Some(path) = std::env::var_os("PATH") else {
+        return Vec::new();
+    };
+    let dirs = std::env::split_paths(&path)
+        .filter(|x| x != launchers)
+        .collect::<Vec<PathBuf>>();
+    index
+        .keys()
+        .filter(|bin| dirs.iter().any(|x| x.join(bin).is_file()))
+        .collect()
+}
+
+/// Lists the binaries with more than one provider and no preferred one among
+/// them
+pub fn ambiguous_bins<'a>(
+    index: &'a BinIndex,
+    preferred: &HashMap<String, String>,
+) -> Vec<(&'a String, &'a Vec<Package>)> {
+    index
+        .iter()
+        .filter(|(bin, pkgs)| {
+            pkgs.len() > 1
+                && !pkgs.iter().any(|x| Some(&x.name) == preferred.get(*bin))
+        })
+        .collect()
+}
+
+/// Warns about the launchers in `launchers` that clash with commands in `PATH`,
+/// and the binaries of `index` with more than one provider and no preferred
+/// one
+pub fn warn_about_bins(
+    index: &BinIndex,
+    launchers: &Path,
+    preferred: &HashMap<String, String>,
+) {
+    let clashes = path_clashes(index, launchers);
+    if !clashes.is_empty() {
+        warn!(
+            "These launchers clash with commands in PATH: {}",
+            clashes
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        );
+    }
+    for (bin, pkgs) in ambiguous_bins(index, preferred) {
+        warn!(
+            "'{bin}' is provided by more than one package: {}",
+            package_names(pkgs)
+        );
+    }
+}
+
+pub fn package_names(pkgs: &[Package]) -> String {
+    pkgs.iter()
+        .map(|x| format!("{}-{}", x.name, x.version))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// Picks the package that `bin` is run from. Without a preferred provider the
+/// first one is used, and the others are warned about.
+fn choose_provider(
+    bin: &str,
+    providers: &[Package],
+    preferred: &HashMap<String, String>,
+) -> Result<Package> {
+    let want = preferred.get(bin);
+    if let Some(x) = providers.iter().find(|x| Some(&x.name) == want) {
+        return Ok(x.clone());
+    }
+    let Some(first) = providers.first() else {
+        bail!("No package found with binary '{bin}'!");
+    };
+    if let Some(want) = want {
+        warn!(
+            "{want} is the preferred provider of '{bin}', but it doesn't \
+            provide it, using {}-{}",
+            first.name, first.version
+        );
+    } else if providers.len() > 1 {
+        warn!(
+            "'{bin}' is provided by {}, using {}-{}. Set the preferred \
+            provider in `providers` in dpt.ron",
+            package_names(providers),
+            first.name,
+            first.version
+        );
+    }
+    Ok(first.clone())
+}
+
+/// Finds the package in `pkgs` that `bin` is run from, using the index that
+/// `dpt rebuild` writes and the one of the user's profile. The user's preferred
+/// providers win over the system's.
+pub fn get_package_for_bin(
+    bin: &str,
+    pkgs: &Vec<OnlinePackage>,
+) -> Result<OnlinePackage> {
+    let dpt = read_dpt_lock_file()?;
+    let mut providers = match read_bin_index(&get_bin_index_location()) {
+        Ok(mut index) => index.remove(bin).unwrap_or_default(),
+        Err(_) => {
+            warn!("No binary index found, run `dpt rebuild` to create it");
+            build_bin_index(&dpt.packages, pkgs)?
+                .remove(bin)
+                .unwrap_or_default()
+        }
+    };
+    let mut preferred = dpt.providers.clone();
+    let uid = get_invoking_uid();
+    if let Ok(profile) = read_profile_lock(uid) {
+        let index = get_profile_dir(uid).join("bin-index.ron");
+        for x in read_bin_index(&index)
+            .unwrap_or_default()
+            .remove(bin)
+            .unwrap_or_default()
+        {
+            if !providers.contains(&x) {
+                providers.push(x);
+            }
+        }
+        preferred.extend(profile.providers);
+    }
+    let providers = providers
+        .into_iter()
+        .filter(|x| package_to_onlinepackage(x, pkgs).is_ok())
+        .collect::<Vec<Package>>();
+    let pkg = choose_provider(bin, &providers, &preferred)?;
+    package_to_onlinepackage(&pkg, pkgs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::get_random_string;
+
+    #[test]
+    fn update_launchers_1() {
+        let dir = std::env::temp_dir()
+            .join(String::from("dpt-launchers-test-") + &get_random_string(10));
+        let launchers = dir.join("bin");
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(&launchers)
+            .unwrap();
+        let dpt = dir.join("dpt");
+        std::fs::write(&dpt, "").unwrap();
+        let a = Package::new("a".into(), "1.0".into());
+        let index = |bins: &[&str]| {
+            bins.iter()
+                .map(|x| (x.to_string(), vec![a.clone()]))
+                .collect::<BinIndex>()
+        };
+
+        update_launchers(&index(&["x", "y"]), &dpt, &launchers).unwrap();
+        std::fs::write(launchers.join("notes"), "").unwrap();
+        std::fs::create_dir(launchers.join("subdir")).unwrap();
+        update_launchers(&index(&["x"]), &dpt, &launchers).unwrap();
+        assert!(launchers.join("x").exists());
+        assert!(!launchers.join("y").exists());
+        // Things that aren't launchers are left alone
+        assert!(launchers.join("notes").exists());
+        assert!(launchers.join("subdir").is_dir());
+
+        let err = update_launchers(&index(&["subdir"]), &dpt, &launchers)
+            .unwrap_err();
+        assert!(err.to_string().contains("isn't a launcher"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn choose_provider_1() {
+        let a = Package::new("a".into(), "1.0".into());
+        let b = Package::new("b".into(), "2.0".into());
+        let mut preferred = HashMap::new();
+        assert_eq!(
+            choose_provider("x", &[a.clone(), b.clone()], &preferred).unwrap(),
+            a
+        );
+        preferred.insert("x".to_string(), "b".to_string());
+        assert_eq!(
+            choose_provider("x", &[a.clone(), b.clone()], &preferred).unwrap(),
+            b
+        );
+        // Falls back to the first when the preferred one doesn't provide it
+        assert_eq!(
+            choose_provider("x", std::slice::from_ref(&a), &preferred).unwrap(),
+            a
+        );
+        assert!(choose_provider("y", &[], &preferred).is_err());
+
+        let index = BinIndex::from([
+            ("x".to_string(), vec![a.clone(), b.clone()]),
+            ("y".to_string(), vec![a.clone(), b.clone()]),
+            ("z".to_string(), vec![a.clone()]),
+            ("w".to_string(), vec![a.clone(), b.clone()]),
+        ]);
+        preferred.insert("w".to_string(), "c".to_string());
+        assert_eq!(
+            ambiguous_bins(&index, &preferred)
+                .into_iter()
+                .map(|(x, _)| x.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["w", "y"]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Host directories that are bound into environments unless the environment
+/// provides them itself
+pub const DEFAULT_BINDS: [&str; 9] = [
+    "/dev", "/mnt", "/media", "/run", "/var", "/home", "/tmp", "/proc", "/sys",
+];
+
+/// A host path that is bind mounted into an environment
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bind {
+    pub src: PathBuf,
+    pub dst: PathBuf,
+    pub read_only: bool,
+    /// Only bind if the environment doesn't have anything at `dst`
+    pub weak: bool,
+}
+
+impl FromStr for Bind {
+    type Err = anyhow::Error;
+
+    /// Parses `SRC[:DST[:ro|rw]]`
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s.split(':').collect::<Vec<&str>>();
+        if parts.len() > 3 {
+            bail!("Invalid bind '{s}', expected SRC[:DST[:ro]]");
+        }
+        let src = PathBuf::from(parts[0]);
+        let dst = match parts.get(1) {
+            Some(x) if !x.is_empty() => PathBuf::from(x),
+            _ => src.clone(),
+        };
+        let read_only = match parts.get(2) {
+            None | Some(&"rw") => false,
+            Some(&"ro") => true,
+            Some(x) => bail!("Unknown bind option '{x}' in '{s}'"),
+        };
+        if !src.is_absolute() || !dst.is_absolute() {
+            bail!("Bind paths must be absolute in '{s}'");
+        }
+        if has_parent_dir(&src) || has_parent_dir(&dst) {
+            bail!("Bind paths can't contain '..' in '{s}'");
+        }
+        Ok(Bind {
+            src,
+            dst,
+            read_only,
+            weak: false,
+        })
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.src.display(), self.dst.display())?;
+        if self.read_only {
+            write!(f, ":ro")?;
+        }
+        Ok(())
+    }
+}
+
+/// A change to the binds of an environment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BindRule {
+    Bind(Bind),
+    /// Keeps a host path out of the environment
+    NoBind(PathBuf),
+}
+
+impl BindRule {
+    /// Parses the `binds` and `no_binds` lists from `pkg.ron` or `dpt.ron`
+    pub fn from_lists(
+        binds: &[String],
+        no_binds: &[String],
+    ) -> Result<Vec<BindRule>> {
+        let mut ret = Vec::<BindRule>::new();
+        for x in binds {
+            ret.push(BindRule::Bind(x.parse()?));
+        }
+        for x in no_binds {
+            ret.push(BindRule::no_bind(x)?);
+        }
+        Ok(ret)
+    }
+
+    pub fn no_bind(path: &str) -> Result<BindRule> {
+        let path = PathBuf::from(path);
+        if !path.is_absolute() {
+            bail!("Path '{}' must be absolute", path.display());
+        }
+        if has_parent_dir(&path) {
+            bail!("Path '{}' can't contain '..'", path.display());
+        }
+        Ok(BindRule::NoBind(path))
+    }
+}
+
+/// What to mount into an environment
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BindPlan {
+    pub binds: Vec<Bind>,
+    /// Paths inside of the environment that are hidden behind an empty
+    /// read-only mount, because they are inside of a bind but were forbidden
+    pub masks: Vec<PathBuf>,
+}
+
+/// Applies `rules` in order on top of the default binds. A later rule wins
+/// over an earlier one.
+pub fn plan_binds(rules: &[BindRule]) -> BindPlan {
+    let mut plan = BindPlan {
+        binds: DEFAULT_BINDS
+            .iter()
+            .map(|x| Bind {
+                src: PathBuf::from(x),
+                dst: PathBuf::from(x),
+                read_only: false,
+                weak: true,
+            })
+            .collect(),
+        masks: Vec::new(),
+    };
+
+    for rule in rules {
+        match rule {
+            BindRule::Bind(bind) => {
+                plan.binds.retain(|x| x.dst != bind.dst);
+                plan.masks.retain(|x| !x.starts_with(&bind.dst));
+                plan.binds.push(bind.clone());
+            }
+            BindRule::NoBind(path) => {
+                plan.binds.retain(|x| !x.src.starts_with(path));
+                for x in &plan.binds {
+                    if let Ok(rel) = path.strip_prefix(&x.src) {
+                        let mask = x.dst.join(rel);
+                        if !plan.masks.contains(&mask) {
+                            plan.masks.push(mask);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Parents have to be mounted before what is inside of them
+    plan.binds.sort_by_key(|x| x.dst.components().count());
+    plan
+}
+
+/// Returns true if `path` has a `..` component, which could lead out of an
+/// environment
+pub fn has_parent_dir(path: &Path) -> bool {
+    path.components().any(|x| x == Component::ParentDir)
+}
+
+/// Returns true if `path` is an empty directory or doesn't exist
+pub fn is_empty_dir(path: &Path) -> bool {
+    match path.read_dir() {
+        Ok(mut x) => x.next().is_none(),
+        Err(_) => !path.exists(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bind() {
+        assert_eq!(
+            "/srv".parse::<Bind>().unwrap(),
+            Bind {
+                src: PathBuf::from("/srv"),
+                dst: PathBuf::from("/srv"),
+                read_only: false,
+                weak: false,
+            }
+        );
+        assert_eq!(
+            "/opt/data:/data:ro".parse::<Bind>().unwrap(),
+            Bind {
+                src: PathBuf::from("/opt/data"),
+                dst: PathBuf::from("/data"),
+                read_only: true,
+                weak: false,
+            }
+        );
+        assert!("/srv::ro".parse::<Bind>().unwrap().read_only);
+        assert!("srv".parse::<Bind>().is_err());
+        assert!("/srv:/srv:rx".parse::<Bind>().is_err());
+        assert!("/a:/b:ro:x".parse::<Bind>().is_err());
+        assert!("/srv:/../etc".parse::<Bind>().is_err());
+        assert!("/srv/../etc".parse::<Bind>().is_err());
+        assert!(BindRule::no_bind("/home/../etc").is_err());
+    }
+
+    #[test]
+    fn plan_binds_1() {
+        let plan = plan_binds(&[
+            BindRule::no_bind("/home").unwrap(),
+            BindRule::no_bind("/var/lib/secret").unwrap(),
+            BindRule::Bind("/opt/data:/data:ro".parse().unwrap()),
+            BindRule::Bind("/srv".parse().unwrap()),
+            BindRule::no_bind("/srv").unwrap(),
+        ]);
+        let dsts = plan
+            .binds
+            .iter()
+            .map(|x| x.dst.to_str().unwrap())
+            .collect::<Vec<&str>>();
+        assert_eq!(
+            dsts,
+            vec![
+                "/dev", "/mnt", "/media", "/run", "/var", "/tmp", "/proc",
+                "/sys", "/data"
+            ]
+        );
+        assert_eq!(plan.masks, vec![PathBuf::from("/var/lib/secret")]);
+    }
+}
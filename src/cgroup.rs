@@ -0,0 +1,438 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    os::{
+        fd::AsFd,
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{info, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{openat, OFlag},
+    sys::{signal::kill, stat::Mode},
+    unistd::{unlinkat, Pid, UnlinkatFlags},
+};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::store::is_rootless;
+
+/// Where the cgroup2 hierarchy is mounted
+pub const CGROUP_MOUNT: &str = "/sys/fs/cgroup";
+
+/// Controllers that limits are set with
+const CONTROLLERS: [&str; 4] = ["cpu", "io", "memory", "pids"];
+
+/// `cpu.max` period in microseconds
+const CPU_PERIOD: u64 = 100000;
+
+/// Resource limits for a program
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Limits {
+    /// In bytes, or a size like `"512M"`
+    #[serde(
+        default,
+        deserialize_with = "deserialize_size",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub memory: Option<u64>,
+    /// How many CPUs worth of time the program may use
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids_max: Option<u64>,
+    /// Between 1 and 10000, 100 is the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub io_weight: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    /// Uses the limits of `other` for those that aren't set
+    pub fn or(self, other: Limits) -> Limits {
+        Limits {
+            memory: self.memory.or(other.memory),
+            cpus: self.cpus.or(other.cpus),
+            pids_max: self.pids_max.or(other.pids_max),
+            io_weight: self.io_weight.or(other.io_weight),
+        }
+    }
+
+    /// Checks that the limits are in range
+    pub fn check(&self) -> Result<()> {
+        if self.memory == Some(0) {
+            bail!("The memory limit must be larger than 0");
+        }
+        if let Some(x) = self.cpus {
+            if !(x > 0.0 && x.is_finite()) {
+                bail!("The CPU limit must be larger than 0, got {x}");
+            }
+        }
+        if let Some(x) = self.io_weight {
+            if !(1..=10000).contains(&x) {
+                bail!("The IO weight must be between 1 and 10000, got {x}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a size in bytes with an optional `K`, `M`, `G` or `T` suffix, which
+/// are powers of 1024
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, shift) = match s.chars().last().map(|x| x.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let num: u64 = num
+        .parse()
+        .context(anyhow!("Invalid size '{s}', expected e.g. 512M"))?;
+    num.checked_mul(1 << shift)
+        .ok_or(anyhow!("Size '{s}' is too large"))
+}
+
+fn deserialize_size<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        String(String),
+    }
+    match Option::<Size>::deserialize(d)? {
+        None => Ok(None),
+        Some(Size::Bytes(x)) => Ok(Some(x)),
+        Some(Size::String(x)) => {
+            parse_size(&x).map(Some).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+/// The cgroup that the calling process is in
+fn own_cgroup() -> Result<PathBuf> {
+    let own = std::fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|x| x.strip_prefix("0::").map(|x| x.to_string()))
+        .ok_or(anyhow!("Not in a cgroup v2 hierarchy"))?;
+    Ok(Path::new(CGROUP_MOUNT).join(own.trim_start_matches('/')))
+}
+
+/// Whether systemd delegated `cgroup`, which it marks with an extended
+/// attribute
+fn is_delegated(cgroup: &Path) -> bool {
+    let mut path = cgroup.as_os_str().as_bytes().to_vec();
+    path.push(0);
+    [c"trusted.delegate", c"user.delegate"].iter().any(|name| {
+        let mut buf = [0u8; 8];
+        let len = unsafe {
+            libc::getxattr(
+                path.as_ptr().cast(),
+                name.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        len > 0 && buf[..len as usize] == *b"1"
+    })
+}
+
+/// Finds the cgroup that dpt may create its subtree in. That is the highest
+/// ancestor of our own cgroup that was delegated, or in rootless mode that
+/// the user owns, which is where systemd delegates to users. Only without a
+/// cgroup manager, when dpt runs in the root of the hierarchy, the root is
+/// used.
+fn delegation_root() -> Result<PathBuf> {
+    let mount = Path::new(CGROUP_MOUNT);
+    if !mount.join("cgroup.controllers").exists() {
+        bail!("cgroup v2 isn't mounted at {CGROUP_MOUNT}");
+    }
+    let own = own_cgroup()?;
+    if !is_rootless() && own == mount {
+        return Ok(own);
+    }
+
+    let uid = uzers::get_current_uid();
+    let mut ret = None;
+    let mut cur = own.clone();
+    while cur.starts_with(mount) && cur != mount {
+        if is_rootless() {
+            if std::fs::metadata(&cur)?.uid() != uid {
+                break;
+            }
+            ret = Some(cur.clone());
+        } else if is_delegated(&cur) {
+            // Everything below a delegated cgroup is delegated too
+            ret = Some(cur.clone());
+        }
+        cur.pop();
+    }
+    ret.ok_or(anyhow!(
+        "dpt's cgroup {} hasn't been delegated, so limits can't be set. \
+        Run dpt in a unit with Delegate=yes, e.g. with systemd-run --scope -p Delegate=yes",
+        own.display()
+    ))
+}
+
+/// Moves the processes in `cgroup` into its child `leaf`, since controllers
+/// can only be enabled for the children of cgroups without processes
+fn move_into_leaf(cgroup: &Path) -> Result<()> {
+    let procs = std::fs::read_to_string(cgroup.join("cgroup.procs"))?;
+    if procs.trim().is_empty() {
+        return Ok(());
+    }
+    let leaf = cgroup.join("leaf");
+    std::fs::DirBuilder::new().recursive(true).create(&leaf)?;
+    for pid in procs.lines() {
+        match std::fs::write(leaf.join("cgroup.procs"), pid) {
+            Ok(()) => {}
+            // It exited in the meantime
+            Err(x) if x.raw_os_error() == Some(libc::ESRCH) => {}
+            Err(x) => {
+                bail!("Failed to move {pid} into {}: {x}", leaf.display())
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Enables the controllers that limits need for the children of `cgroup`
+fn enable_controllers(cgroup: &Path) -> Result<()> {
+    let available = std::fs::read_to_string(cgroup.join("cgroup.controllers"))?;
+    let enable = CONTROLLERS
+        .iter()
+        .filter(|x| available.split_whitespace().any(|y| y == **x))
+        .map(|x| format!("+{x}"))
+        .collect::<Vec<String>>()
+        .join(" ");
+    std::fs::write(cgroup.join("cgroup.subtree_control"), enable).context(
+        anyhow!("Failed to enable controllers in {}", cgroup.display()),
+    )?;
+    Ok(())
+}
+
+/// A transient cgroup that a program is run in, which is removed when it is
+/// dropped. It is kept open, so that it can still be reached once the mounts
+/// of the environment have changed.
+pub struct Cgroup {
+    name: String,
+    parent: File,
+    dir: File,
+    procs: File,
+}
+
+impl Cgroup {
+    /// Creates a cgroup with `limits` under dpt's subtree
+    pub fn create(limits: &Limits) -> Result<Cgroup> {
+        limits.check()?;
+        let root = delegation_root()?;
+        // E.g. a scope with Delegate=yes that dpt was started in
+        if root == own_cgroup()? {
+            move_into_leaf(&root)?;
+        }
+        let subtree = root.join("dpt");
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(&subtree)?;
+        enable_controllers(&root)?;
+        enable_controllers(&subtree)?;
+
+        let name = format!("run-{}", std::process::id());
+        let path = subtree.join(&name);
+        // Left behind by an earlier process with the same pid
+        if path.exists() {
+            std::fs::remove_dir(&path)?;
+        }
+        std::fs::create_dir(&path)
+            .context(anyhow!("Failed to create cgroup {}", path.display()))?;
+
+        let mut settings = Vec::<(&str, String)>::new();
+        if let Some(x) = limits.memory {
+            settings.push(("memory.max", x.to_string()));
+        }
+        if let Some(x) = limits.cpus {
+            let quota = (x * CPU_PERIOD as f64).round() as u64;
+            settings
+                .push(("cpu.max", format!("{} {CPU_PERIOD}", quota.max(1000))));
+        }
+        if let Some(x) = limits.pids_max {
+            settings.push(("pids.max", x.to_string()));
+        }
+        if let Some(x) = limits.io_weight {
+            settings.push(("io.weight", format!("default {x}")));
+        }
+        let cgroup = Cgroup {
+            name,
+            parent: File::open(&subtree)?,
+            dir: File::open(&path)?,
+            procs: std::fs::OpenOptions::new()
+                .write(true)
+                .open(path.join("cgroup.procs"))?,
+        };
+        let available =
+            std::fs::read_to_string(path.join("cgroup.controllers"))?;
+        for (file, value) in settings {
+            let controller = file.split('.').next().unwrap_or(file);
+            if !available.split_whitespace().any(|x| x == controller) {
+                bail!("The {controller} cgroup controller isn't available");
+            }
+            if let Err(x) = std::fs::write(path.join(file), &value) {
+                bail!("Failed to set {file} to {value}: {x}");
+            }
+        }
+        Ok(cgroup)
+    }
+
+    /// Moves the calling process into the cgroup. Only allocation-free calls
+    /// are made, so this is safe to use in `pre_exec`.
+    pub fn enter(&self) -> std::io::Result<()> {
+        // Writing 0 moves the writer
+        (&self.procs).write_all(b"0")
+    }
+
+    fn read(&self, file: &str) -> Option<String> {
+        let fd = openat(
+            self.dir.as_fd(),
+            file,
+            OFlag::O_RDONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .ok()?;
+        let mut ret = String::new();
+        File::from(fd).read_to_string(&mut ret).ok()?;
+        Some(ret)
+    }
+
+    /// Logs the peak usage of the program
+    pub fn report(&self) {
+        if let Some(x) = self.read("memory.peak") {
+            if let Ok(x) = x.trim().parse::<u64>() {
+                info!("Peak memory: {:.1} MiB", x as f64 / (1 << 20) as f64);
+            }
+        }
+        if let Some(x) = self.read("cpu.stat") {
+            if let Some(x) = x
+                .lines()
+                .find_map(|x| x.strip_prefix("usage_usec "))
+                .and_then(|x| x.trim().parse::<u64>().ok())
+            {
+                info!("CPU time: {:.2}s", x as f64 / 1000000.0);
+            }
+        }
+        if let Some(x) = self.read("pids.peak") {
+            info!("Peak processes: {}", x.trim());
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(x) = kill_and_remove(&self.parent, &self.name) {
+            warn!("{x}");
+        }
+    }
+}
+
+/// Kills whatever is left in the cgroup `name` under `parent` and removes it
+fn kill_and_remove(parent: &File, name: &str) -> Result<()> {
+    if let Ok(fd) = openat(
+        parent.as_fd(),
+        Path::new(name).join("cgroup.kill").as_path(),
+        OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    ) {
+        let _ = File::from(fd).write_all(b"1");
+    }
+    // Killed processes take a moment to leave
+    for _ in 0..50 {
+        match unlinkat(parent.as_fd(), name, UnlinkatFlags::RemoveDir) {
+            Ok(()) => return Ok(()),
+            Err(Errno::EBUSY) => {
+                std::thread::sleep(std::time::Duration::from_millis(10))
+            }
+            Err(x) => bail!("Failed to remove cgroup {name}: {x}"),
+        }
+    }
+    bail!("Failed to remove cgroup {name}, it is still in use");
+}
+
+/// Kills and removes the cgroups of runs whose dpt is gone, and returns their
+/// names
+pub fn remove_stale() -> Result<Vec<String>> {
+    let Ok(root) = delegation_root() else {
+        return Ok(Vec::new()); // Limits were never set
+    };
+    let subtree = root.join("dpt");
+    let Ok(parent) = File::open(&subtree) else {
+        return Ok(Vec::new());
+    };
+    let mut ret = Vec::new();
+    for ent in std::fs::read_dir(&subtree)? {
+        let name = ent?.file_name().to_string_lossy().to_string();
+        let Some(pid) = name
+            .strip_prefix("run-")
+            .and_then(|x| x.parse::<i32>().ok())
+        else {
+            continue;
+        };
+        if kill(Pid::from_raw(pid), None) != Err(Errno::ESRCH) {
+            continue;
+        }
+        kill_and_remove(&parent, &name)?;
+        ret.push(name);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_1() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("4k").unwrap(), 4096);
+        assert_eq!(parse_size("512M").unwrap(), 512 << 20);
+        assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn limits_in_pkg_ron() {
+        let limits: Limits = ron::from_str(
+            "#![enable(implicit_some)]\n(memory: \"1G\", cpus: 1.5, io_weight: 50)",
+        )
+        .unwrap();
+        assert_eq!(
+            limits,
+            Limits {
+                memory: Some(1 << 30),
+                cpus: Some(1.5),
+                pids_max: None,
+                io_weight: Some(50),
+            }
+        );
+        let again: Limits =
+            ron::from_str(&ron::to_string(&limits).unwrap()).unwrap();
+        assert_eq!(again, limits);
+        assert!(Limits {
+            io_weight: Some(0),
+            ..Default::default()
+        }
+        .check()
+        .is_err());
+    }
+}
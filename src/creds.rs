@@ -0,0 +1,114 @@
+use std::{os::fd::OwnedFd, path::Path};
+
+use anyhow::Result;
+use nix::{
+    fcntl::{open, OFlag},
+    sys::stat::Mode,
+    unistd::{
+        getgid, getgroups, setfsgid, setfsuid, setgroups, setresgid, setresuid,
+        Gid, Uid,
+    },
+};
+
+use crate::dpt_file::{DptFile, User};
+
+/// The user and groups that a program in an environment runs as
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: Uid,
+    pub gid: Gid,
+    /// Supplementary groups
+    pub groups: Vec<Gid>,
+}
+
+impl Credentials {
+    /// Gets the credentials of the user that invoked dpt. Setuid doesn't change
+    /// the real gid or the supplementary groups, so they are still the
+    /// invoking user's.
+    pub fn of_invoking_user(uid: u32) -> Result<Credentials> {
+        Ok(Credentials {
+            uid: Uid::from_raw(uid),
+            gid: getgid(),
+            groups: getgroups()?,
+        })
+    }
+
+    /// Switches to these credentials. The groups are set first since that
+    /// needs root, which is given up by setting the uid. Only allocation-free
+    /// calls are made, so this is safe to use in `pre_exec`.
+    pub fn apply(&self, set_groups: bool) -> nix::Result<()> {
+        if set_groups {
+            setgroups(&self.groups)?;
+        }
+        setresgid(self.gid, self.gid, self.gid)?;
+        setresuid(self.uid, self.uid, self.uid)?;
+        Ok(())
+    }
+
+    /// Opens `path` with the filesystem permissions of these credentials, so
+    /// that root isn't used to reach paths that they can't. Only the
+    /// filesystem ids change, the supplementary groups are already theirs.
+    pub fn open_path(&self, path: &Path) -> nix::Result<OwnedFd> {
+        let gid = setfsgid(self.gid);
+        let uid = setfsuid(self.uid);
+        let ret = open(path, OFlag::O_PATH | OFlag::O_CLOEXEC, Mode::empty());
+        setfsuid(uid);
+        setfsgid(gid);
+        ret
+    }
+}
+
+/// Finds the user with `uid` in the users that `base` was built from
+pub fn lookup_user(uid: u32, dpt: &DptFile) -> Option<&User> {
+    dpt.users.iter().find(|x| x.uid == uid as u64)
+}
+
+/// Returns true if the `no-new-privs` config option is set to `true`
+pub fn no_new_privs_enabled() -> bool {
+    crate::config::get_config_option("no-new-privs")
+        .is_some_and(|x| x.trim() == "true")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::CommandExt;
+
+    use super::*;
+
+    fn status_field(status: &str, name: &str) -> String {
+        status
+            .lines()
+            .find_map(|x| x.strip_prefix(&format!("{name}:")))
+            .unwrap()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+
+    #[test]
+    #[ignore = "changing credentials needs root, run with --ignored"]
+    fn apply_credentials() {
+        let creds = Credentials {
+            uid: Uid::from_raw(65534),
+            gid: Gid::from_raw(65533),
+            groups: vec![Gid::from_raw(100), Gid::from_raw(200)],
+        };
+        let mut cmd = std::process::Command::new("cat");
+        cmd.arg("/proc/self/status");
+        unsafe {
+            cmd.pre_exec(move || {
+                creds.apply(true)?;
+                nix::sys::prctl::set_no_new_privs()?;
+                Ok(())
+            });
+        }
+        let out = cmd.output().unwrap();
+        assert!(out.status.success());
+        let status = String::from_utf8(out.stdout).unwrap();
+
+        assert_eq!(status_field(&status, "Uid"), "65534 65534 65534 65534");
+        assert_eq!(status_field(&status, "Gid"), "65533 65533 65533 65533");
+        assert_eq!(status_field(&status, "Groups"), "100 200");
+        assert_eq!(status_field(&status, "NoNewPrivs"), "1");
+    }
+}
@@ -1,10 +1,13 @@
-use std::{path::PathBuf, str::FromStr};
+use std::path::{Path, PathBuf};
 
+use crate::init::InitConfig;
+use crate::lint::LintIssue;
 use crate::pkg::Package;
 use crate::store::get_dpt_dir;
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
+use ron::error::{Position, SpannedError};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct User {
@@ -24,26 +27,229 @@ pub struct Group {
     pub members: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// The file that each user, group and service of a merged `dpt.ron` comes
+/// from, by name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Origins {
+    pub users: HashMap<String, PathBuf>,
+    pub groups: HashMap<String, PathBuf>,
+    pub services: HashMap<String, PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct DptFile {
+    /// Files merged in before this one, relative to it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default)]
     pub packages: Vec<Package>,
+    #[serde(default)]
     pub users: Vec<User>,
+    #[serde(default)]
     pub groups: Vec<Group>,
     pub services: Option<HashMap<String, Vec<String>>>,
+    /// Default binds for every environment, as `SRC[:DST[:ro]]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub binds: Vec<String>,
+    /// Host paths that environments don't get by default
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_binds: Vec<String>,
+    /// Which package runs a binary that more than one package provides
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub providers: HashMap<String, String>,
+    /// How dpt sets the system up when it is PID 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub init: Option<InitConfig>,
+    /// Sections that are merged in last on the host with that name, or with
+    /// `--profile`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hosts: BTreeMap<String, DptFile>,
+    #[serde(skip)]
+    pub origins: Origins,
+}
+
+impl DptFile {
+    /// Merges `other` into this file. Packages, users and groups replace the
+    /// ones with the same name, or are added. Services of a target are added
+    /// to its list, binds are added, and providers and `init` are replaced.
+    pub fn merge(&mut self, other: DptFile) {
+        for pkg in other.packages {
+            match self.packages.iter_mut().find(|x| x.name == pkg.name) {
+                Some(x) => *x = pkg,
+                None => self.packages.push(pkg),
+            }
+        }
+        for user in other.users {
+            match self.users.iter_mut().find(|x| x.username == user.username) {
+                Some(x) => *x = user,
+                None => self.users.push(user),
+            }
+        }
+        for group in other.groups {
+            match self
+                .groups
+                .iter_mut()
+                .find(|x| x.groupname == group.groupname)
+            {
+                Some(x) => *x = group,
+                None => self.groups.push(group),
+            }
+        }
+        if let Some(services) = other.services {
+            let ours = self.services.get_or_insert_with(HashMap::new);
+            for (target, units) in services {
+                let list = ours.entry(target).or_default();
+                for unit in units {
+                    if !list.contains(&unit) {
+                        list.push(unit);
+                    }
+                }
+            }
+        }
+        for bind in other.binds {
+            if !self.binds.contains(&bind) {
+                self.binds.push(bind);
+            }
+        }
+        for path in other.no_binds {
+            if !self.no_binds.contains(&path) {
+                self.no_binds.push(path);
+            }
+        }
+        self.providers.extend(other.providers);
+        if other.init.is_some() {
+            self.init = other.init;
+        }
+        for (name, section) in other.hosts {
+            self.hosts.entry(name).or_default().merge(section);
+        }
+        self.origins.users.extend(other.origins.users);
+        self.origins.groups.extend(other.origins.groups);
+        self.origins.services.extend(other.origins.services);
+    }
+
+    /// Records `path` as the origin of the users, groups and services of this
+    /// file and its host sections
+    fn set_origin(&mut self, path: &Path) {
+        for user in &self.users {
+            self.origins
+                .users
+                .insert(user.username.clone(), path.to_path_buf());
+        }
+        for group in &self.groups {
+            self.origins
+                .groups
+                .insert(group.groupname.clone(), path.to_path_buf());
+        }
+        let mut services = Vec::<&String>::new();
+        for (target, units) in self.services.iter().flatten() {
+            services.push(target);
+            services.extend(units);
+        }
+        if let Some(init) = &self.init {
+            services.extend(init.services.iter().map(|x| &x.name));
+        }
+        for x in services {
+            self.origins.services.insert(x.clone(), path.to_path_buf());
+        }
+        for section in self.hosts.values_mut() {
+            section.set_origin(path);
+        }
+    }
+
+    /// Merges in the host section for `profile`, or for the hostname without
+    /// one, and drops the others
+    pub fn select_host(mut self, profile: Option<&str>) -> Result<DptFile> {
+        let mut hosts = std::mem::take(&mut self.hosts);
+        let name = match profile {
+            Some(x) if !hosts.contains_key(x) => {
+                bail!("There is no host section called '{x}'!")
+            }
+            Some(x) => x.to_string(),
+            None => nix::unistd::gethostname()?.to_string_lossy().to_string(),
+        };
+        if let Some(section) = hosts.remove(&name) {
+            self.merge(section);
+        }
+        Ok(self)
+    }
 }
 
 pub fn get_dpt_file_location() -> PathBuf {
     get_dpt_dir().join("dpt.ron")
 }
 
+/// The file that `name` comes from in `origins`, or `dpt.ron` if it isn't
+/// known
+pub fn origin_of(origins: &HashMap<String, PathBuf>, name: &str) -> PathBuf {
+    origins
+        .get(name)
+        .cloned()
+        .unwrap_or_else(get_dpt_file_location)
+}
+
 pub fn get_dpt_lock_location() -> PathBuf {
     get_dpt_dir().join("dpt.lock")
 }
 
-pub fn read_dpt_file() -> Result<DptFile> {
-    Ok(parse_dpt_file(&std::fs::read_to_string(
-        get_dpt_file_location(),
-    )?)?)
+/// Reads `dpt.ron` with everything that it includes merged in, and the host
+/// section for `profile` or the hostname
+pub fn read_dpt_file(profile: Option<&str>) -> Result<DptFile> {
+    Ok(read_dpt_files(profile)?.0)
+}
+
+/// Like `read_dpt_file`, but also returns every file that was read, as it was
+/// before merging
+pub fn read_dpt_files(
+    profile: Option<&str>,
+) -> Result<(DptFile, Vec<(PathBuf, DptFile)>)> {
+    let mut loaded = Vec::new();
+    let dpt =
+        load_dpt_file(&get_dpt_file_location(), &mut Vec::new(), &mut loaded)?
+            .select_host(profile)?;
+    Ok((dpt, loaded))
+}
+
+/// Reads the file at `path` after merging the files that it includes, in
+/// order. `including` are the files that are being read already, so that
+/// include loops are found, and each file is added to `loaded`.
+fn load_dpt_file(
+    path: &Path,
+    including: &mut Vec<PathBuf>,
+    loaded: &mut Vec<(PathBuf, DptFile)>,
+) -> Result<DptFile> {
+    let path = path
+        .canonicalize()
+        .context(anyhow!("Failed to read {}", path.display()))?;
+    if including.contains(&path) {
+        bail!("{} includes itself!", path.display());
+    }
+    let text = std::fs::read_to_string(&path)
+        .context(anyhow!("Failed to read {}", path.display()))?;
+    let mut file = parse_dpt_file(&text)
+        .map_err(|x| anyhow!(describe_parse_error(&path, &text, &x)))?;
+    file.set_origin(&path);
+    loaded.push((path.clone(), file.clone()));
+    if file
+        .hosts
+        .values()
+        .any(|x| !x.include.is_empty() || !x.hosts.is_empty())
+    {
+        bail!(
+            "Host sections in {} can't include files or have host sections!",
+            path.display()
+        );
+    }
+
+    including.push(path.clone());
+    let mut ret = DptFile::default();
+    for x in std::mem::take(&mut file.include) {
+        let dir = path.parent().unwrap_or(Path::new("/"));
+        ret.merge(load_dpt_file(&dir.join(x), including, loaded)?);
+    }
+    including.pop();
+    ret.merge(file);
+    Ok(ret)
 }
 
 pub fn read_dpt_lock_file() -> Result<DptFile> {
@@ -52,14 +258,145 @@ pub fn read_dpt_lock_file() -> Result<DptFile> {
     )?)?)
 }
 
-pub fn parse_dpt_file(file: &str) -> Result<DptFile> {
-    let dpt_file = String::from_str("#![enable(implicit_some)]\n")?;
-    Ok(ron::from_str(&(dpt_file + file))?)
+pub fn parse_dpt_file(file: &str) -> Result<DptFile, SpannedError> {
+    ron::from_str(&(String::from("#![enable(implicit_some)]\n") + file))
+        .map_err(|mut x| {
+            // Not counting the line that was added
+            x.position.line = x.position.line.saturating_sub(1);
+            x
+        })
+}
+
+/// Describes an error in `text`, which was read from `path`, with its position
+/// and the line that it is on
+pub fn describe_parse_error(
+    path: &Path,
+    text: &str,
+    err: &SpannedError,
+) -> String {
+    let Position { line, col } = err.position;
+    let mut ret = format!("{}:{line}:{col}: {}", path.display(), err.code);
+    if let Some(x) = line.checked_sub(1).and_then(|x| text.lines().nth(x)) {
+        ret +=
+            &format!("\n    {x}\n    {}^", " ".repeat(col.saturating_sub(1)));
+    }
+    ret
+}
+
+/// Finds users and groups that are listed more than once in a single
+/// `dpt.ron` or included file, or one of its host sections. Merging keeps the
+/// last one, so this has to be checked before.
+pub fn check_dpt_file_names(dpt: &DptFile) -> Vec<LintIssue> {
+    let mut issues = Vec::<LintIssue>::new();
+    let sections = std::iter::once((String::new(), dpt)).chain(
+        dpt.hosts
+            .iter()
+            .map(|(name, x)| (format!("Host section '{name}': "), x)),
+    );
+    for (prefix, section) in sections {
+        let mut seen = HashSet::<&String>::new();
+        for user in &section.users {
+            if !seen.insert(&user.username) {
+                issues.push(LintIssue::error(format!(
+                    "{prefix}User '{}' is listed more than once!",
+                    user.username
+                )));
+            }
+        }
+        let mut seen = HashSet::<&String>::new();
+        for group in &section.groups {
+            if !seen.insert(&group.groupname) {
+                issues.push(LintIssue::error(format!(
+                    "{prefix}Group '{}' is listed more than once!",
+                    group.groupname
+                )));
+            }
+        }
+    }
+    issues
+}
+
+/// Checks that the users, groups and services of a merged `dpt.ron` make sense
+/// together, along with the file each issue comes from
+pub fn check_dpt_file(dpt: &DptFile) -> Vec<(PathBuf, LintIssue)> {
+    let mut issues = Vec::<(PathBuf, LintIssue)>::new();
+    let user_origin = |x: &str| origin_of(&dpt.origins.users, x);
+    let group_origin = |x: &str| origin_of(&dpt.origins.groups, x);
+
+    let mut uids = HashMap::<u64, &String>::new();
+    for user in &dpt.users {
+        if let Some(x) = uids.insert(user.uid, &user.username) {
+            issues.push((
+                user_origin(&user.username),
+                LintIssue::error(format!(
+                    "Users '{x}' and '{}' have the same uid {}!",
+                    user.username, user.uid
+                )),
+            ));
+        }
+    }
+    let mut gids = HashMap::<u64, &String>::new();
+    for group in &dpt.groups {
+        if let Some(x) = gids.insert(group.gid, &group.groupname) {
+            issues.push((
+                group_origin(&group.groupname),
+                LintIssue::error(format!(
+                    "Groups '{x}' and '{}' have the same gid {}!",
+                    group.groupname, group.gid
+                )),
+            ));
+        }
+    }
+
+    for user in &dpt.users {
+        if !gids.contains_key(&user.gid) {
+            issues.push((
+                user_origin(&user.username),
+                LintIssue::warning(format!(
+                    "User '{}' has gid {}, which no group has",
+                    user.username, user.gid
+                )),
+            ));
+        }
+    }
+    for group in &dpt.groups {
+        for member in &group.members {
+            if !dpt.users.iter().any(|x| &x.username == member) {
+                issues.push((
+                    group_origin(&group.groupname),
+                    LintIssue::warning(format!(
+                        "Group '{}' has member '{member}', who isn't a user",
+                        group.groupname
+                    )),
+                ));
+            }
+        }
+    }
+
+    let mut services = Vec::<&String>::new();
+    for (target, units) in dpt.services.iter().flatten() {
+        services.push(target);
+        services.extend(units);
+    }
+    if let Some(init) = &dpt.init {
+        services.extend(init.services.iter().map(|x| &x.name));
+    }
+    for x in services {
+        if x.contains('/') {
+            issues.push((
+                origin_of(&dpt.origins.services, x),
+                LintIssue::error(format!("Service name '{x}' contains a '/'!")),
+            ));
+        }
+    }
+
+    issues
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::lint::Severity;
 
     #[test]
     fn package_array() {
@@ -257,4 +594,125 @@ mod test {
             ]))
         );
     }
+
+    #[test]
+    fn includes_1() {
+        let dir = std::env::temp_dir().join(
+            String::from("dpt-include-test-")
+                + &crate::run::get_random_string(10),
+        );
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(dir.join("common"))
+            .unwrap();
+        std::fs::write(
+            dir.join("common/base.ron"),
+            r#"(
+    packages: [(name: "bash", version: ""), (name: "fish", version: "3.0")],
+    groups: [(groupname: "wheel", gid: 10, members: [])],
+    services: {"multi-user.target": ["getty@1.service"]},
+    hosts: {"web": (packages: [(name: "nginx", version: "")])},
+)"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("dpt.ron"),
+            r#"(
+    include: ["common/base.ron"],
+    packages: [(name: "fish", version: "4.0.0")],
+    services: {"multi-user.target": ["sshd.service"]},
+    hosts: {"web": (services: {"multi-user.target": ["nginx.service"]})},
+)"#,
+        )
+        .unwrap();
+
+        let out = load_dpt_file(
+            &dir.join("dpt.ron"),
+            &mut Vec::new(),
+            &mut Vec::new(),
+        )
+        .unwrap()
+        .select_host(Some("web"))
+        .unwrap();
+        assert_eq!(
+            out.packages,
+            vec![
+                Package::new("bash".into(), "".into()),
+                Package::new("fish".into(), "4.0.0".into()),
+                Package::new("nginx".into(), "".into()),
+            ]
+        );
+        assert_eq!(
+            out.services,
+            Some(HashMap::from([(
+                "multi-user.target".to_string(),
+                vec![
+                    "getty@1.service".to_string(),
+                    "sshd.service".to_string(),
+                    "nginx.service".to_string()
+                ]
+            )]))
+        );
+        assert!(out.hosts.is_empty() && out.include.is_empty());
+        let base = dir.join("common/base.ron").canonicalize().unwrap();
+        let top = dir.join("dpt.ron").canonicalize().unwrap();
+        assert_eq!(origin_of(&out.origins.groups, "wheel"), base);
+        assert_eq!(origin_of(&out.origins.services, "getty@1.service"), base);
+        assert_eq!(origin_of(&out.origins.services, "nginx.service"), top);
+        assert!(load_dpt_file(
+            &dir.join("dpt.ron"),
+            &mut Vec::new(),
+            &mut Vec::new()
+        )
+        .unwrap()
+        .select_host(Some("db"))
+        .is_err());
+
+        std::fs::write(
+            dir.join("common/base.ron"),
+            r#"(include: ["../dpt.ron"])"#,
+        )
+        .unwrap();
+        assert!(load_dpt_file(
+            &dir.join("dpt.ron"),
+            &mut Vec::new(),
+            &mut Vec::new()
+        )
+        .is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_dpt_file_1() {
+        let dpt = parse_dpt_file(
+            r#"(
+    users: [
+        (username: "a", password: "", uid: 1000, gid: 1000, gecos: "", home: "/home/a", shell: "/bin/sh"),
+        (username: "b", password: "", uid: 1000, gid: 1001, gecos: "", home: "/home/b", shell: "/bin/sh"),
+        (username: "a", password: "", uid: 1002, gid: 1000, gecos: "", home: "/home/a", shell: "/bin/sh"),
+    ],
+    groups: [
+        (groupname: "a", gid: 1000, members: ["a", "c"]),
+        (groupname: "d", gid: 1000, members: []),
+    ],
+    services: {"multi-user.target": ["getty@1.service", "../evil.service"]},
+)"#,
+        )
+        .unwrap();
+        let names = check_dpt_file_names(&dpt);
+        assert_eq!(names.len(), 1);
+        assert!(names[0].message.contains("'a'"));
+
+        let issues = check_dpt_file(&dpt);
+        let count = |severity| {
+            issues
+                .iter()
+                .filter(|(_, x)| x.severity == severity)
+                .count()
+        };
+        // Same uid, same gid and the service
+        assert_eq!(count(Severity::Error), 3);
+        // The gid of b and member c
+        assert_eq!(count(Severity::Warning), 2);
+    }
 }
@@ -0,0 +1,321 @@
+use std::{io::Read, path::Path};
+
+use anyhow::{bail, Result};
+
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_LOAD: u32 = 1;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_SONAME: u64 = 14;
+
+/// The dynamic linking information of an ELF file
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct ElfInfo {
+    /// The `PT_INTERP` of the file, e.g. `/lib64/ld-linux-x86-64.so.2`
+    pub interpreter: Option<String>,
+    /// All of the `DT_NEEDED` entries
+    pub needed: Vec<String>,
+    /// The `DT_SONAME` of the file, if it is a shared library
+    pub soname: Option<String>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    is_64: bool,
+    little_endian: bool,
+}
+
+/// Adds offsets read from the file, which may be made up to overflow
+fn add(a: u64, b: u64) -> Result<u64> {
+    match a.checked_add(b) {
+        Some(x) => Ok(x),
+        None => bail!("Truncated ELF file!"),
+    }
+}
+
+fn mul(a: u64, b: u64) -> Result<u64> {
+    match a.checked_mul(b) {
+        Some(x) => Ok(x),
+        None => bail!("Truncated ELF file!"),
+    }
+}
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&self, off: u64) -> Result<[u8; N]> {
+        let end = add(off, N as u64)?;
+        match self.data.get(off as usize..end as usize) {
+            Some(x) if end <= usize::MAX as u64 => Ok(x.try_into()?),
+            _ => bail!("Truncated ELF file!"),
+        }
+    }
+
+    fn u16(&self, off: u64) -> Result<u16> {
+        let b = self.bytes::<2>(off)?;
+        Ok(if self.little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    }
+
+    fn u32(&self, off: u64) -> Result<u32> {
+        let b = self.bytes::<4>(off)?;
+        Ok(if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    fn u64(&self, off: u64) -> Result<u64> {
+        let b = self.bytes::<8>(off)?;
+        Ok(if self.little_endian {
+            u64::from_le_bytes(b)
+        } else {
+            u64::from_be_bytes(b)
+        })
+    }
+
+    /// Reads a word, which is 4 bytes on ELF32 and 8 bytes on ELF64
+    fn word(&self, off: u64) -> Result<u64> {
+        if self.is_64 {
+            self.u64(off)
+        } else {
+            Ok(self.u32(off)? as u64)
+        }
+    }
+
+    fn c_str(&self, off: u64) -> Result<String> {
+        let rest = match self.data.get(off as usize..) {
+            Some(x) if off <= usize::MAX as u64 => x,
+            _ => bail!("String offset out of range!"),
+        };
+        let end = rest.iter().position(|x| *x == 0).unwrap_or(rest.len());
+        Ok(String::from_utf8_lossy(&rest[..end]).to_string())
+    }
+}
+
+struct ProgramHeader {
+    p_type: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+/// Returns true if the file starts with the ELF magic
+pub fn is_elf(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    match std::fs::File::open(path) {
+        Ok(mut f) => f.read_exact(&mut magic).is_ok() && magic == *b"\x7fELF",
+        Err(_) => false,
+    }
+}
+
+/// Reads the dynamic linking information from an ELF file on disk.
+/// Returns None if the file is not an ELF file.
+pub fn read_elf(path: &Path) -> Result<Option<ElfInfo>> {
+    if !is_elf(path) {
+        return Ok(None);
+    }
+    parse_elf(&std::fs::read(path)?)
+}
+
+/// Parses the dynamic linking information of an ELF file.
+/// Returns None if the data is not an ELF file.
+pub fn parse_elf(data: &[u8]) -> Result<Option<ElfInfo>> {
+    if data.len() < 16 || data[..4] != *b"\x7fELF" {
+        return Ok(None);
+    }
+    let r = Reader {
+        data,
+        is_64: match data[4] {
+            1 => false,
+            2 => true,
+            x => bail!("Unknown ELF class {x}!"),
+        },
+        little_endian: match data[5] {
+            1 => true,
+            2 => false,
+            x => bail!("Unknown ELF data encoding {x}!"),
+        },
+    };
+
+    let (phoff, phentsize, phnum) = if r.is_64 {
+        (r.u64(32)?, r.u16(54)? as u64, r.u16(56)? as u64)
+    } else {
+        (r.u32(28)? as u64, r.u16(42)? as u64, r.u16(44)? as u64)
+    };
+
+    let mut headers = Vec::<ProgramHeader>::new();
+    for i in 0..phnum {
+        let base = add(phoff, mul(i, phentsize)?)?;
+        headers.push(if r.is_64 {
+            ProgramHeader {
+                p_type: r.u32(base)?,
+                offset: r.u64(add(base, 8)?)?,
+                vaddr: r.u64(add(base, 16)?)?,
+                filesz: r.u64(add(base, 32)?)?,
+            }
+        } else {
+            ProgramHeader {
+                p_type: r.u32(base)?,
+                offset: r.u32(add(base, 4)?)? as u64,
+                vaddr: r.u32(add(base, 8)?)? as u64,
+                filesz: r.u32(add(base, 16)?)? as u64,
+            }
+        });
+    }
+
+    let mut info = ElfInfo::default();
+
+    for ph in headers.iter().filter(|x| x.p_type == PT_INTERP) {
+        info.interpreter = Some(r.c_str(ph.offset)?);
+    }
+
+    let dynamic = match headers.iter().find(|x| x.p_type == PT_DYNAMIC) {
+        Some(x) => x,
+        None => return Ok(Some(info)),
+    };
+
+    let entsize = if r.is_64 { 16 } else { 8 };
+    let wordsize = entsize / 2;
+    let mut strtab_vaddr: Option<u64> = None;
+    let mut needed_offsets = Vec::<u64>::new();
+    let mut soname_offset: Option<u64> = None;
+    let mut off = dynamic.offset;
+    let end = add(dynamic.offset, dynamic.filesz)?;
+    while add(off, entsize)? <= end {
+        let tag = r.word(off)?;
+        let val = r.word(add(off, wordsize)?)?;
+        match tag {
+            DT_NULL => break,
+            DT_NEEDED => needed_offsets.push(val),
+            DT_STRTAB => strtab_vaddr = Some(val),
+            DT_SONAME => soname_offset = Some(val),
+            _ => {}
+        }
+        off += entsize;
+    }
+
+    let strtab_vaddr = match strtab_vaddr {
+        Some(x) => x,
+        None => return Ok(Some(info)),
+    };
+    // DT_STRTAB is a virtual address, so map it back into the file
+    let strtab = match headers.iter().find(|x| {
+        x.p_type == PT_LOAD
+            && strtab_vaddr >= x.vaddr
+            && x.vaddr
+                .checked_add(x.filesz)
+                .is_some_and(|end| strtab_vaddr < end)
+    }) {
+        Some(x) => add(strtab_vaddr - x.vaddr, x.offset)?,
+        None => bail!("Failed to locate the dynamic string table!"),
+    };
+
+    for x in needed_offsets {
+        info.needed.push(r.c_str(add(strtab, x)?)?);
+    }
+    if let Some(x) = soname_offset {
+        info.soname = Some(r.c_str(add(strtab, x)?)?);
+    }
+
+    Ok(Some(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a small little endian ELF64 file with an interpreter, two
+    /// needed libraries and a soname.
+    fn build_test_elf() -> Vec<u8> {
+        let mut data = vec![0u8; 0x200];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = 2; // ELFCLASS64
+        data[5] = 1; // ELFDATA2LSB
+        data[32..40].copy_from_slice(&64u64.to_le_bytes()); // e_phoff
+        data[54..56].copy_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        data[56..58].copy_from_slice(&3u16.to_le_bytes()); // e_phnum
+
+        let mut ph = |i: usize, p_type: u32, offset: u64, filesz: u64| {
+            let base = 64 + i * 56;
+            data[base..base + 4].copy_from_slice(&p_type.to_le_bytes());
+            data[base + 8..base + 16].copy_from_slice(&offset.to_le_bytes());
+            let vaddr = offset + 0x1000;
+            data[base + 16..base + 24].copy_from_slice(&vaddr.to_le_bytes());
+            data[base + 32..base + 40].copy_from_slice(&filesz.to_le_bytes());
+        };
+        ph(0, PT_LOAD, 0, 0x200);
+        ph(1, PT_INTERP, 0x100, 0x20);
+        ph(2, PT_DYNAMIC, 0x140, 0x50);
+
+        let interp = b"/lib/ld-test.so.1\0";
+        data[0x100..0x100 + interp.len()].copy_from_slice(interp);
+
+        let strtab = b"\0libc.so.6\0libm.so.6\0libtest.so.1\0";
+        data[0x190..0x190 + strtab.len()].copy_from_slice(strtab);
+
+        let dynamic: [(u64, u64); 5] = [
+            (DT_NEEDED, 1),
+            (DT_NEEDED, 11),
+            (DT_SONAME, 21),
+            (DT_STRTAB, 0x1190),
+            (DT_NULL, 0),
+        ];
+        for (i, (tag, val)) in dynamic.iter().enumerate() {
+            let base = 0x140 + i * 16;
+            data[base..base + 8].copy_from_slice(&tag.to_le_bytes());
+            data[base + 8..base + 16].copy_from_slice(&val.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn parse_elf_1() {
+        let info = parse_elf(&build_test_elf()).unwrap().unwrap();
+        assert_eq!(
+            info,
+            ElfInfo {
+                interpreter: Some("/lib/ld-test.so.1".to_string()),
+                needed: vec!["libc.so.6".to_string(), "libm.so.6".to_string()],
+                soname: Some("libtest.so.1".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_elf_not_elf() {
+        assert_eq!(parse_elf(b"#!/bin/sh\necho hi\n").unwrap(), None);
+        assert_eq!(parse_elf(b"").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_elf_truncated() {
+        let data = build_test_elf();
+        parse_elf(&data[..80]).expect_err("Truncated file was accepted");
+    }
+
+    #[test]
+    fn parse_elf_overflow() {
+        let mut data = build_test_elf();
+        data[32..40].copy_from_slice(&(u64::MAX - 8).to_le_bytes());
+        parse_elf(&data).expect_err("Overflowing e_phoff was accepted");
+
+        // The offset and size of PT_DYNAMIC
+        let mut data = build_test_elf();
+        data[64 + 2 * 56 + 8..64 + 2 * 56 + 16]
+            .copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        data[64 + 2 * 56 + 32..64 + 2 * 56 + 40]
+            .copy_from_slice(&u64::MAX.to_le_bytes());
+        parse_elf(&data).expect_err("Overflowing PT_DYNAMIC was accepted");
+
+        // A string offset past the end of the address space
+        let mut data = build_test_elf();
+        data[0x140 + 8..0x140 + 16].copy_from_slice(&u64::MAX.to_le_bytes());
+        parse_elf(&data).expect_err("Overflowing DT_NEEDED was accepted");
+    }
+}
@@ -1,51 +1,130 @@
 use std::{
-    fs::{self, hard_link, read_link},
-    os::unix::fs::symlink,
+    collections::{BTreeMap, HashMap},
+    fs::{self, hard_link, read_link, File},
+    os::unix::fs::{symlink, MetadataExt},
     path::{Path, PathBuf},
 };
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use log::warn;
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag, Flock, FlockArg},
+    sys::signal::kill,
+    unistd::Pid,
+};
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
 use crate::{
+    binds::{BindRule, DEFAULT_BINDS},
+    cgroup::Limits,
+    envvars::is_unsafe_var,
+    hash::to_hex,
     pkg::{Glue, Package},
     repo::{
         package_to_onlinepackage, resolve_dependencies_for_packages,
         OnlinePackage,
     },
-    run::join_proper,
-    store::get_dpt_dir,
+    run::{
+        get_random_string, get_run_location, join_proper,
+        make_dpt_dir_writable, open_in_root, DirGuard,
+    },
+    signals,
+    store::{get_dpt_dir, is_rootless},
 };
 
-/// Generates the environment for a package, version solving to find dependencies.
-pub fn generate_environment_for_packages(
-    pkgs_selected: &Vec<Package>,
-    pkgs: &Vec<OnlinePackage>,
-    out_path: &Path,
-    dev_env: bool,
-) -> Result<()> {
-    let packages_resolved =
-        resolve_dependencies_for_packages(&pkgs, &pkgs_selected)?;
-    if let Ok(x) = std::fs::exists(out_path) {
-        if x {
-            std::fs::remove_dir_all(out_path)?;
+/// A cached environment that is in use. The environment won't be pruned while
+/// this, or any process that inherited its lock, is alive.
+pub struct Environment {
+    pub path: PathBuf,
+    /// The lower layers of the overlay, highest priority first. Empty for
+    /// hard linked environments.
+    pub layers: Vec<PathBuf>,
+    /// Variables that the packages set for programs
+    pub vars: BTreeMap<String, String>,
+    /// The packages' binds, applied in order
+    pub bind_rules: Vec<BindRule>,
+    /// One of the selected packages asks to be sandboxed
+    pub sandbox: bool,
+    /// Default limits of the selected packages
+    pub limits: Limits,
+    _lock: Flock<File>,
+}
+
+impl Environment {
+    /// Returns true if there is a file or symlink at `rel` in the environment
+    pub fn contains(&self, rel: &Path) -> bool {
+        let exists = |dir: &Path| {
+            let p = dir.join(rel);
+            p.is_file() || p.is_symlink()
+        };
+        if self.layers.is_empty() {
+            exists(&self.path)
+        } else {
+            self.layers.iter().any(|x| exists(x))
         }
     }
-    std::fs::DirBuilder::new()
-        .recursive(true)
-        .create(out_path)?;
-    if get_dpt_dir().join("base").is_dir() {
-        generate_environment_for_directory(
-            &get_dpt_dir().join("base"),
-            &out_path,
-        )?;
-    } else {
-        warn!("`base` is not found!");
+}
+
+/// How the files of an environment are put together
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnvironmentBackend {
+    /// Hard links to every file, which needs the store and `run` to be on the
+    /// same filesystem
+    HardLink,
+    /// An overlayfs of `base` and the packages with a tmpfs on top
+    Overlay,
+}
+
+/// Gets the environment backend from the `env-backend` config option
+pub fn get_environment_backend() -> EnvironmentBackend {
+    match crate::config::get_config_option("env-backend")
+        .as_deref()
+        .map(str::trim)
+    {
+        None | Some("hardlink") => EnvironmentBackend::HardLink,
+        Some("overlay") => EnvironmentBackend::Overlay,
+        Some(x) => {
+            warn!("Unknown environment backend `{x}`, using `hardlink`");
+            EnvironmentBackend::HardLink
+        }
     }
+}
+
+/// Everything that goes into building an environment
+pub struct EnvironmentSpec {
+    /// The resolved packages, highest priority first
+    pub packages: Vec<OnlinePackage>,
+    pub glues: Vec<Glue>,
+    /// The packages that glues pull files from
+    pub glue_dirs: Vec<PathBuf>,
+    /// Variables from the packages' `env`, where higher priority packages win
+    pub vars: BTreeMap<String, String>,
+    /// The packages' binds, lowest priority first so that higher priority
+    /// packages win
+    pub bind_rules: Vec<BindRule>,
+    /// One of the selected packages asks to be sandboxed
+    pub sandbox: bool,
+    /// Default limits of the selected packages, where the first one wins
+    pub limits: Limits,
+}
+
+/// Version solves the dependencies of the selected packages and collects
+/// their glues and variables.
+pub fn resolve_environment(
+    pkgs_selected: &Vec<Package>,
+    pkgs: &Vec<OnlinePackage>,
+    dev_env: bool,
+) -> Result<EnvironmentSpec> {
+    let packages = resolve_dependencies_for_packages(&pkgs, &pkgs_selected)?;
 
     let mut glues: Vec<Glue> = Vec::new();
-    for x in packages_resolved {
+    let mut vars = BTreeMap::<String, String>::new();
+    let mut bind_rules = Vec::<BindRule>::new();
+    let mut sandbox = false;
+    let mut limits = Limits::default();
+    for x in &packages {
         let config = crate::pkg::get_package_config(&std::fs::read_to_string(
             Path::new(&x.url).join("dpt/pkg.ron"),
         )?)?;
@@ -55,10 +134,29 @@ pub fn generate_environment_for_packages(
             }
             glues.push(glue);
         }
-        generate_environment_for_directory(Path::new(&x.url), &out_path)?;
+        for (name, value) in config.env {
+            if is_unsafe_var(&name) {
+                bail!(
+                    "Variable {name} of {}-{} can't be set by a package!",
+                    x.name,
+                    x.version
+                );
+            }
+            vars.entry(name).or_insert(value);
+        }
+        let mut rules =
+            BindRule::from_lists(&config.binds, &config.no_binds).context(
+                anyhow!("Invalid binds in {}-{}", x.name, x.version),
+            )?;
+        rules.append(&mut bind_rules);
+        bind_rules = rules;
+        if pkgs_selected.iter().any(|y| y.name == x.name) {
+            sandbox |= config.sandbox == Some(true);
+            limits = limits.or(config.limits.unwrap_or_default());
+        }
     }
 
-    let pkg_dirs = if dev_env {
+    let glue_dirs = if dev_env {
         pkgs_selected
             .iter()
             .map(|x| package_to_onlinepackage(&x, &pkgs))
@@ -71,13 +169,482 @@ pub fn generate_environment_for_packages(
     .iter()
     .map(|x| Path::new(&x.url).to_path_buf())
     .collect();
-    for glue in glues {
-        generate_glue_for_directory(&glue, &pkg_dirs, &out_path)?;
+
+    Ok(EnvironmentSpec {
+        packages,
+        glues,
+        glue_dirs,
+        vars,
+        bind_rules,
+        sandbox,
+        limits,
+    })
+}
+
+/// A file that more than one package in an environment provides
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileConflict {
+    /// Path relative to the root of the environment
+    pub path: PathBuf,
+    /// The package whose file ends up in the environment
+    pub winner: String,
+    /// The package whose file is hidden
+    pub loser: String,
+    /// True if the winner lists the file in `overrides`
+    pub intentional: bool,
+    /// True if the loser lists the file in `overrides`, but has a lower
+    /// priority, so the override doesn't take effect
+    pub loser_overrides: bool,
+}
+
+impl std::fmt::Display for FileConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "/{}: {} overrides {}",
+            self.path.display(),
+            self.winner,
+            self.loser
+        )?;
+        if self.intentional {
+            write!(f, " (intentional)")?;
+        } else if self.loser_overrides {
+            write!(
+                f,
+                " ({} declares an override, but has a lower priority)",
+                self.loser
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A directory whose files go into an environment
+pub struct Layer {
+    /// The name shown in conflicts, e.g. `base` or `name-version`
+    pub name: String,
+    pub path: PathBuf,
+    pub overrides: Vec<glob::Pattern>,
+}
+
+/// Finds the files provided by more than one layer. Layers are given highest
+/// priority first, like in environments.
+pub fn find_conflicts(layers: &[Layer]) -> Vec<FileConflict> {
+    let mut owners = HashMap::<PathBuf, usize>::new();
+    let mut conflicts = Vec::<FileConflict>::new();
+
+    for (i, layer) in layers.iter().enumerate() {
+        for ent in WalkDir::new(&layer.path).into_iter().filter_map(|e| e.ok())
+        {
+            if ent.file_type().is_dir() {
+                continue;
+            }
+            let Ok(rel) = ent.path().strip_prefix(&layer.path) else {
+                continue;
+            };
+            if rel.starts_with("dpt") {
+                continue;
+            }
+            let Some(&owner) = owners.get(rel) else {
+                owners.insert(rel.to_path_buf(), i);
+                continue;
+            };
+            let winner = &layers[owner];
+            if same_file(&winner.path.join(rel), ent.path()) {
+                continue;
+            }
+            let overridden =
+                |x: &Layer| x.overrides.iter().any(|p| p.matches_path(rel));
+            conflicts.push(FileConflict {
+                path: rel.to_path_buf(),
+                winner: winner.name.clone(),
+                loser: layer.name.clone(),
+                intentional: overridden(winner),
+                loser_overrides: overridden(layer),
+            });
+        }
+    }
+    conflicts
+}
+
+/// Returns true if two paths are links to the same file, or symlinks with the
+/// same target
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.symlink_metadata(), b.symlink_metadata()) {
+        (Ok(x), Ok(y)) => {
+            if x.is_symlink() && y.is_symlink() {
+                read_link(a).ok() == read_link(b).ok()
+            } else {
+                x.dev() == y.dev() && x.ino() == y.ino()
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Finds the file conflicts between `base` and the packages of an environment
+pub fn environment_conflicts(
+    spec: &EnvironmentSpec,
+) -> Result<Vec<FileConflict>> {
+    let mut layers = Vec::<Layer>::new();
+    if get_dpt_dir().join("base").is_dir() {
+        layers.push(Layer {
+            name: "base".to_string(),
+            path: get_dpt_dir().join("base"),
+            overrides: Vec::new(),
+        });
+    }
+    for x in &spec.packages {
+        let config = crate::pkg::get_package_config(&std::fs::read_to_string(
+            Path::new(&x.url).join("dpt/pkg.ron"),
+        )?)?;
+        layers.push(Layer {
+            name: format!("{}-{}", x.name, x.version),
+            path: PathBuf::from(&x.url),
+            overrides: config
+                .overrides
+                .iter()
+                .filter_map(|g| {
+                    glob::Pattern::new(g.trim_start_matches('/')).ok()
+                })
+                .collect(),
+        });
+    }
+    Ok(find_conflicts(&layers))
+}
+
+/// Finds the file conflicts in the environment of each of `pkgs_selected`
+pub fn conflicts_for_packages(
+    pkgs_selected: &[Package],
+    pkgs: &Vec<OnlinePackage>,
+) -> Result<Vec<(Package, Vec<FileConflict>)>> {
+    let mut ret = Vec::<(Package, Vec<FileConflict>)>::new();
+    for pkg in pkgs_selected {
+        let spec = resolve_environment(&vec![pkg.clone()], pkgs, false)?;
+        ret.push((pkg.clone(), environment_conflicts(&spec)?));
     }
+    Ok(ret)
+}
+
+/// Gets a string that identifies when a package directory was last installed
+fn install_stamp(dir: &Path) -> String {
+    match std::fs::metadata(dir.join("dpt/.done")) {
+        Ok(x) => format!("{}.{}", x.mtime(), x.mtime_nsec()),
+        Err(_) => "none".to_string(),
+    }
+}
+
+/// Computes the key that a cached environment is stored under. It changes
+/// whenever the packages, their installs, the glues or `base` change.
+pub fn environment_key(
+    spec: &EnvironmentSpec,
+    backend: EnvironmentBackend,
+) -> String {
+    let mut h = Sha256::new();
+    h.update(format!("backend {:?}\n", backend).as_bytes());
+    match std::fs::metadata(get_dpt_dir().join("base")) {
+        Ok(x) => {
+            h.update(format!("base {} {}\n", x.ino(), x.mtime()).as_bytes())
+        }
+        Err(_) => h.update(b"base none\n"),
+    }
+    for pkg in &spec.packages {
+        h.update(
+            format!(
+                "pkg {} {} {} {}\n",
+                pkg.name,
+                pkg.version,
+                pkg.url,
+                install_stamp(Path::new(&pkg.url))
+            )
+            .as_bytes(),
+        );
+    }
+    for glue in &spec.glues {
+        h.update(format!("glue {:?}\n", glue).as_bytes());
+    }
+    if !spec.glues.is_empty() {
+        for dir in &spec.glue_dirs {
+            h.update(
+                format!("gluedir {} {}\n", dir.display(), install_stamp(dir))
+                    .as_bytes(),
+            );
+        }
+    }
+    to_hex(&h.finalize())
+}
 
+/// Computes the key of the cached environment for a set of packages without
+/// generating it
+pub fn environment_key_for_packages(
+    pkgs_selected: &Vec<Package>,
+    pkgs: &Vec<OnlinePackage>,
+    dev_env: bool,
+) -> Result<String> {
+    let spec = resolve_environment(pkgs_selected, pkgs, dev_env)?;
+    Ok(environment_key(&spec, get_environment_backend()))
+}
+
+/// Gets the environment for a set of packages from the cache, generating it
+/// first if needed.
+pub fn get_environment_for_packages(
+    pkgs_selected: &Vec<Package>,
+    pkgs: &Vec<OnlinePackage>,
+    dev_env: bool,
+) -> Result<Environment> {
+    let spec = resolve_environment(pkgs_selected, pkgs, dev_env)?;
+    let backend = get_environment_backend();
+    let key = environment_key(&spec, backend);
+    let cache = get_run_location();
+    make_dpt_dir_writable()?;
+    std::fs::DirBuilder::new().recursive(true).create(&cache)?;
+
+    let lock_file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(cache.join(key.clone() + ".lock"))?;
+    let lock = Flock::lock(lock_file, FlockArg::LockShared)
+        .map_err(|(_, e)| e)
+        .context("Failed to lock the environment")?;
+    // Keep the lock held by everything that runs inside the environment
+    fcntl(&*lock, FcntlArg::F_SETFD(FdFlag::empty()))?;
+
+    let path = cache.join(&key);
+    if !path.is_dir() {
+        // The pid tells `dpt cleanup` whether it was left behind
+        let tmp = DirGuard::new(cache.join(format!(
+            ".{key}.tmp-{}-{}",
+            std::process::id(),
+            get_random_string(6)
+        )));
+        for x in environment_conflicts(&spec)? {
+            if !x.intentional {
+                warn!("File conflict {x}");
+            }
+        }
+        let (ret, sig) = signals::hold_signals(|| match backend {
+            EnvironmentBackend::HardLink => {
+                generate_environment(&spec, tmp.path())
+            }
+            EnvironmentBackend::Overlay => {
+                generate_overlay_environment(&spec, tmp.path())
+            }
+        })?;
+        if let Some(sig) = sig {
+            drop(tmp);
+            std::process::exit(128 + sig as i32);
+        }
+        ret?;
+        // If another run generated the same environment first, the guard
+        // removes ours
+        if std::fs::rename(tmp.path(), &path).is_err() && !path.is_dir() {
+            bail!("Failed to move environment into {}", path.display());
+        }
+    }
+
+    Ok(match backend {
+        EnvironmentBackend::HardLink => Environment {
+            path,
+            layers: Vec::new(),
+            vars: spec.vars,
+            bind_rules: spec.bind_rules,
+            sandbox: spec.sandbox,
+            limits: spec.limits,
+            _lock: lock,
+        },
+        EnvironmentBackend::Overlay => {
+            let mut layers = Vec::<PathBuf>::new();
+            if get_dpt_dir().join("base").is_dir() {
+                layers.push(get_dpt_dir().join("base"));
+            }
+            for x in &spec.packages {
+                layers.push(PathBuf::from(&x.url));
+            }
+            layers.push(path.join("glue"));
+            Environment {
+                path: path.join("rw"),
+                layers,
+                vars: spec.vars,
+                bind_rules: spec.bind_rules,
+                sandbox: spec.sandbox,
+                limits: spec.limits,
+                _lock: lock,
+            }
+        }
+    })
+}
+
+/// Removes every cached environment that isn't in use, except for those of
+/// named environments
+pub fn prune_environment_cache() -> Result<()> {
+    let cache = get_run_location();
+    let Ok(entries) = std::fs::read_dir(&cache) else {
+        return Ok(());
+    };
+    let roots = crate::named_env::cached_environment_keys();
+    for ent in entries {
+        let path = ent?.path();
+        let Some(key) = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_suffix(".lock"))
+        else {
+            continue;
+        };
+        if roots.iter().any(|x| x == key) {
+            continue;
+        }
+        let Ok(lock_file) = File::open(&path) else {
+            continue;
+        };
+        let Ok(_lock) = Flock::lock(lock_file, FlockArg::LockExclusiveNonblock)
+        else {
+            continue; // Still in use
+        };
+        for dir in std::fs::read_dir(&cache)? {
+            let dir = dir?.path();
+            let name = dir
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            if name == key || name.starts_with(&format!(".{key}.tmp-")) {
+                std::fs::remove_dir_all(&dir)?;
+            }
+        }
+    }
     Ok(())
 }
 
+/// Removes environments that were being generated by a dpt that is gone, and
+/// returns them
+pub fn remove_stale_environments() -> Result<Vec<PathBuf>> {
+    let cache = get_run_location();
+    let Ok(entries) = std::fs::read_dir(&cache) else {
+        return Ok(Vec::new());
+    };
+    let mut ret = Vec::new();
+    for ent in entries {
+        let path = ent?.path();
+        let Some((key, rest)) = path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_prefix('.'))
+            .and_then(|x| x.split_once(".tmp-"))
+        else {
+            continue;
+        };
+        // Nobody can be generating it without holding the lock
+        let unlocked = File::open(cache.join(format!("{key}.lock")))
+            .ok()
+            .and_then(|x| Flock::lock(x, FlockArg::LockExclusiveNonblock).ok())
+            .is_some();
+        let gone = rest
+            .split_once('-')
+            .and_then(|(pid, _)| pid.parse::<i32>().ok())
+            .is_some_and(|pid| {
+                kill(Pid::from_raw(pid), None) == Err(Errno::ESRCH)
+            });
+        if unlocked || gone {
+            std::fs::remove_dir_all(&path)?;
+            ret.push(path);
+        }
+    }
+    Ok(ret)
+}
+
+/// Builds an environment into `out_path`
+pub fn generate_environment(
+    spec: &EnvironmentSpec,
+    out_path: &Path,
+) -> Result<()> {
+    if let Ok(x) = std::fs::exists(out_path) {
+        if x {
+            std::fs::remove_dir_all(out_path)?;
+        }
+    }
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .create(out_path)?;
+    if get_dpt_dir().join("base").is_dir() {
+        generate_environment_for_directory(
+            &get_dpt_dir().join("base"),
+            &out_path,
+        )?;
+    } else {
+        warn!("`base` is not found!");
+    }
+
+    for x in &spec.packages {
+        generate_environment_for_directory(Path::new(&x.url), &out_path)?;
+    }
+
+    for glue in &spec.glues {
+        generate_glue_for_directory(glue, &spec.glue_dirs, &out_path)?;
+    }
+
+    create_mountpoints(spec, out_path)
+}
+
+/// Creates the mountpoints of the default binds, the binds of the packages,
+/// the dpt directory and the old root in a hard linked environment, which runs
+/// don't change since it is shared between them
+fn create_mountpoints(spec: &EnvironmentSpec, out_path: &Path) -> Result<()> {
+    let mut paths = DEFAULT_BINDS
+        .iter()
+        .map(|x| (PathBuf::from(x), true))
+        .collect::<Vec<(PathBuf, bool)>>();
+    paths.push((get_dpt_dir(), true));
+    paths.push((PathBuf::from("/.old_root"), true));
+    for rule in &spec.bind_rules {
+        if let BindRule::Bind(x) = rule {
+            paths.push((x.dst.clone(), !x.src.is_file()));
+        }
+    }
+    for (path, dir) in paths {
+        // Nothing is created where the environment has a symlink
+        if let Err(x) = open_in_root(out_path, &path, true, dir) {
+            warn!("No mountpoint for {}: {x}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Builds the parts of an overlay environment into `out_path`. `glue` holds the
+/// lowest layer with the glued files, and `rw` is where the second stage
+/// mounts the overlay.
+pub fn generate_overlay_environment(
+    spec: &EnvironmentSpec,
+    out_path: &Path,
+) -> Result<()> {
+    if !get_dpt_dir().join("base").is_dir() {
+        warn!("`base` is not found!");
+    }
+    let glue_path = out_path.join("glue");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .create(&glue_path)?;
+    std::fs::DirBuilder::new().create(out_path.join("rw"))?;
+
+    for glue in &spec.glues {
+        generate_glue_for_directory(glue, &spec.glue_dirs, &glue_path)?;
+    }
+
+    Ok(())
+}
+
+/// Gets the dpt binary that `Bin` glues link to. Rootless dpt isn't installed
+/// in the dpt directory, so it copies itself there.
+pub fn get_dpt_binary() -> Result<PathBuf> {
+    let me = get_dpt_dir().join("dpt");
+    if !me.exists() && is_rootless() {
+        let tmp = get_dpt_dir().join(format!(".dpt.{}", get_random_string(6)));
+        std::fs::copy(std::env::current_exe()?, &tmp)?;
+        std::fs::rename(&tmp, &me)?;
+    }
+    Ok(me)
+}
+
 pub fn generate_glue_for_directory(
     glue: &Glue,
     pkg_dirs: &Vec<PathBuf>,
@@ -85,7 +652,7 @@ pub fn generate_glue_for_directory(
 ) -> Result<()> {
     match glue {
         Glue::Bin => {
-            let me = Path::new("/dpt/dpt");
+            let me = get_dpt_binary()?;
             for dir in pkg_dirs {
                 for p in vec!["usr/bin", "bin"] {
                     if let Ok(dir_contents) = std::fs::read_dir(dir.join(&p)) {
@@ -189,3 +756,74 @@ pub fn generate_environment_for_directory(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_conflicts_1() {
+        let dir = std::env::temp_dir()
+            .join(String::from("dpt-env-test-") + &get_random_string(10));
+        for (pkg, file, contents) in [
+            ("a", "usr/lib/libfoo.so", "a"),
+            ("b", "usr/lib/libfoo.so", "b"),
+            ("b", "usr/share/b", "b"),
+            ("c", "usr/share/b", "c"),
+            ("c", "dpt/pkg.ron", "c"),
+            ("a", "dpt/pkg.ron", "a"),
+        ] {
+            let path = dir.join(pkg).join(file);
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .create(path.parent().unwrap())
+                .unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(dir.join("d/usr/lib"))
+            .unwrap();
+        std::fs::hard_link(
+            dir.join("a/usr/lib/libfoo.so"),
+            dir.join("d/usr/lib/libfoo.so"),
+        )
+        .unwrap();
+
+        let layer = |name: &str, overrides: &[&str]| Layer {
+            name: name.to_string(),
+            path: dir.join(name),
+            overrides: overrides
+                .iter()
+                .map(|x| glob::Pattern::new(x).unwrap())
+                .collect(),
+        };
+        let conflicts = find_conflicts(&[
+            layer("a", &["usr/lib/*"]),
+            layer("b", &[]),
+            layer("c", &["usr/share/*"]),
+            layer("d", &[]),
+        ]);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            conflicts,
+            vec![
+                FileConflict {
+                    path: PathBuf::from("usr/lib/libfoo.so"),
+                    winner: "a".to_string(),
+                    loser: "b".to_string(),
+                    intentional: true,
+                    loser_overrides: false,
+                },
+                FileConflict {
+                    path: PathBuf::from("usr/share/b"),
+                    winner: "b".to_string(),
+                    loser: "c".to_string(),
+                    intentional: false,
+                    loser_overrides: true,
+                },
+            ]
+        );
+    }
+}
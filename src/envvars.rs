@@ -0,0 +1,120 @@
+use std::{collections::BTreeMap, ffi::OsString};
+
+/// Variables that are passed into environments by default. A trailing `*`
+/// matches any suffix.
+pub const DEFAULT_ALLOWED_VARS: [&str; 14] = [
+    "TERM",
+    "COLORTERM",
+    "NO_COLOR",
+    "LANG",
+    "LANGUAGE",
+    "LC_*",
+    "TZ",
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+    "XAUTHORITY",
+    "XDG_*",
+];
+
+/// `PATH` inside of environments
+pub const ENV_PATH: &str = "/usr/bin:/bin";
+
+/// Returns true if a variable changes how the dynamic linker or libc behave,
+/// so it must never get through a privileged dpt
+pub fn is_unsafe_var(name: &str) -> bool {
+    name.starts_with("LD_") || name == "GCONV_PATH"
+}
+
+/// Removes unsafe variables from dpt's own environment. This has to happen
+/// before any privileged work is done.
+pub fn strip_unsafe_vars() {
+    for (name, _) in std::env::vars_os() {
+        if is_unsafe_var(&name.to_string_lossy()) {
+            std::env::remove_var(name);
+        }
+    }
+}
+
+/// Returns true if a variable is in the default allowlist
+pub fn is_allowed_var(name: &str) -> bool {
+    DEFAULT_ALLOWED_VARS
+        .iter()
+        .any(|x| match x.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == *x,
+        })
+}
+
+/// Builds the variables for a program in an environment out of the caller's
+/// `vars`. Allowed variables are passed through, `PATH` is reset, then the
+/// packages' variables are added. Variables in `keep` are passed through last,
+/// so they win over everything else, unless they are unsafe.
+pub fn program_vars(
+    vars: impl Iterator<Item = (OsString, OsString)>,
+    package_vars: &BTreeMap<String, String>,
+    keep: &[String],
+) -> BTreeMap<OsString, OsString> {
+    let mut ret = BTreeMap::<OsString, OsString>::new();
+    let mut kept = BTreeMap::<OsString, OsString>::new();
+    for (name, value) in vars {
+        let s = name.to_string_lossy();
+        if is_unsafe_var(&s) {
+            continue;
+        }
+        if keep.iter().any(|x| *x == s) {
+            kept.insert(name, value);
+        } else if is_allowed_var(&s) {
+            ret.insert(name, value);
+        }
+    }
+    ret.insert("PATH".into(), ENV_PATH.into());
+    for (name, value) in package_vars {
+        ret.insert(name.into(), value.into());
+    }
+    ret.extend(kept);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_vars_1() {
+        let vars = [
+            ("TERM", "xterm"),
+            ("LC_TIME", "C"),
+            ("PATH", "/home/me/bin:/usr/bin"),
+            ("LD_PRELOAD", "/tmp/evil.so"),
+            ("SECRET", "1"),
+            ("EDITOR", "vi"),
+            ("PYTHONPATH", "/home/me/py"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (OsString::from(k), OsString::from(v)));
+        let package_vars = BTreeMap::from([
+            ("PYTHONPATH".to_string(), "/usr/lib/python".to_string()),
+            ("EDITOR".to_string(), "nano".to_string()),
+        ]);
+
+        let ret = program_vars(
+            vars,
+            &package_vars,
+            &["EDITOR".to_string(), "LD_PRELOAD".to_string()],
+        );
+        let expected = [
+            ("EDITOR", "vi"),
+            ("LC_TIME", "C"),
+            ("PATH", ENV_PATH),
+            ("PYTHONPATH", "/usr/lib/python"),
+            ("TERM", "xterm"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (OsString::from(k), OsString::from(v)))
+        .collect::<BTreeMap<OsString, OsString>>();
+        assert_eq!(ret, expected);
+    }
+}
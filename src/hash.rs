@@ -0,0 +1,53 @@
+use std::path::Path;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+
+/// Formats a digest as a lowercase hex string
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+/// Computes the SHA-256 of a byte slice as a lowercase hex string
+pub fn sha256(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Computes the SHA-256 of a file as a lowercase hex string
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut f = std::fs::File::open(path)?;
+    let mut h = Sha256::new();
+    std::io::copy(&mut f, &mut h)?;
+    Ok(to_hex(&h.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn sha256_streaming() {
+        let data = vec![0x61u8; 1000];
+        let mut h = Sha256::new();
+        for chunk in data.chunks(7) {
+            h.update(chunk);
+        }
+        assert_eq!(to_hex(&h.finalize()), sha256(&data));
+    }
+}
@@ -0,0 +1,514 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::ProgressBar;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hash::sha256_file,
+    pkg::{decompress_pkg_read, get_package_config, PackageConfig},
+    repo::{
+        shard_prefix, OnlinePackage, RepositoryIndex, ShardManifest,
+        SonameIndex, SHARD_MANIFEST,
+    },
+    run::get_random_string,
+    scan::is_library_path,
+};
+
+/// Where `dpt gen-index` remembers the files it indexed, relative to the
+/// repository. It is only used to skip hashing unchanged files, and doesn't
+/// need to be published.
+pub const INDEX_CACHE: &str = ".index-cache.ron";
+
+/// A `.dpt` file as it was when it was indexed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct CachedFile {
+    size: u64,
+    /// In nanoseconds since the epoch
+    mtime: i64,
+    sha256: String,
+}
+
+/// Finds all of the `.dpt` files below a directory
+fn find_packages(root: &Path) -> Vec<PathBuf> {
+    let mut ret = walkdir::WalkDir::new(root)
+        .follow_links(true)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.path().extension().is_some_and(|x| x == "dpt"))
+        .map(|x| x.path().to_owned())
+        .collect::<Vec<PathBuf>>();
+    ret.sort();
+    ret
+}
+
+/// Converts the path of a package into the URL that is put in the index
+fn package_url(root: &Path, path: &Path) -> Result<String> {
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    let rel = rel.strip_prefix("./").unwrap_or(rel);
+    Ok(rel
+        .to_str()
+        .ok_or(anyhow!("Failed to convert file path into a str"))?
+        .to_string())
+}
+
+/// Reads the metadata of a package by decompressing it
+fn read_package(
+    path: &Path,
+    url: String,
+    sha256: String,
+) -> Result<Option<OnlinePackage>> {
+    let download_size = std::fs::metadata(path)?.len();
+    let mut pkg = decompress_pkg_read(std::fs::File::open(path)?)?;
+    let mut cfg: Option<PackageConfig> = None;
+    let mut installed_size: u64 = 0;
+    let mut libraries = Vec::<String>::new();
+    for pkg_ent in pkg.entries()? {
+        let mut pkg_ent = pkg_ent?;
+        installed_size += pkg_ent.size();
+        let ent_path = pkg_ent.path()?.to_path_buf();
+        if !pkg_ent.header().entry_type().is_dir() && is_library_path(&ent_path)
+        {
+            if let Some(x) = ent_path.file_name() {
+                let x = x.to_string_lossy().to_string();
+                if !libraries.contains(&x) {
+                    libraries.push(x);
+                }
+            }
+        }
+        if ent_path == Path::new("dpt/pkg.ron")
+            || ent_path == Path::new("./dpt/pkg.ron")
+        {
+            let mut buf = String::new();
+            pkg_ent.read_to_string(&mut buf)?;
+            cfg = Some(get_package_config(&buf)?);
+        }
+    }
+    let Some(mut cfg) = cfg else {
+        warn!("{} has no dpt/pkg.ron! Skipping...", path.display());
+        return Ok(None);
+    };
+
+    cfg.installed_size = Some(installed_size);
+    cfg.download_size = Some(download_size);
+    let mut ret = OnlinePackage::from_package_config(cfg, url);
+    libraries.sort();
+    ret.provides = libraries;
+    ret.sha256 = Some(sha256);
+    Ok(Some(ret))
+}
+
+/// Gets the modification time of a file in nanoseconds since the epoch
+fn mtime_nanos(metadata: &std::fs::Metadata) -> i64 {
+    metadata
+        .mtime()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(metadata.mtime_nsec())
+}
+
+/// Gets the index entry for a package, reusing the entry from the previous
+/// index if the cache says the file has the same size and modification time
+/// as then, or otherwise if it has the same hash. The file is returned as it
+/// should be cached.
+fn index_package(
+    root: &Path,
+    path: &Path,
+    previous: &HashMap<String, OnlinePackage>,
+    cache: &HashMap<String, CachedFile>,
+) -> Result<Option<(OnlinePackage, CachedFile)>> {
+    let url = package_url(root, path)?;
+    let metadata = std::fs::metadata(path)?;
+    let mut file = CachedFile {
+        size: metadata.len(),
+        mtime: mtime_nanos(&metadata),
+        sha256: String::new(),
+    };
+
+    let old = previous
+        .get(&url)
+        .filter(|x| x.download_size == Some(file.size));
+    if let (Some(old), Some(cached)) = (old, cache.get(&url)) {
+        if old.sha256.as_ref() == Some(&cached.sha256)
+            && cached.size == file.size
+            && cached.mtime == file.mtime
+        {
+            return Ok(Some((old.clone(), cached.clone())));
+        }
+    }
+
+    file.sha256 = sha256_file(path)?;
+    if let Some(old) = old {
+        if old.sha256.as_ref() == Some(&file.sha256) {
+            return Ok(Some((old.clone(), file)));
+        }
+    }
+    let ret = read_package(path, url, file.sha256.clone())
+        .context(anyhow!("Failed to read package {}", path.display()))?;
+    Ok(ret.map(|x| (x, file)))
+}
+
+/// Checks for duplicate packages and packages whose file name doesn't match
+/// their configuration. Duplicates are returned as errors.
+pub fn check_index(packages: &[OnlinePackage]) -> Result<()> {
+    let mut seen = HashMap::<(&str, &str), &str>::new();
+    let mut duplicates = Vec::<String>::new();
+    for pkg in packages {
+        if let Some(other) =
+            seen.insert((&pkg.name, &pkg.version), pkg.url.as_str())
+        {
+            duplicates.push(format!(
+                "{} {} is provided by both {} and {}",
+                pkg.name, pkg.version, other, pkg.url
+            ));
+        }
+
+        let expected = pkg.name.clone() + "-" + &pkg.version + ".dpt";
+        let file_name = Path::new(&pkg.url)
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if file_name != expected {
+            warn!(
+                "{} contains {} {}, but should be named {}",
+                pkg.url, pkg.name, pkg.version, expected
+            );
+        }
+    }
+    if !duplicates.is_empty() {
+        bail!("Duplicate packages found!\n{}", duplicates.join("\n"));
+    }
+    Ok(())
+}
+
+/// Writes a file by writing to a temporary file and renaming it into place,
+/// so that readers never see a partially written file.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or(anyhow!("Invalid path {}", path.display()))?
+        .to_string_lossy()
+        .to_string();
+    let tmp = path.with_file_name(
+        String::from(".") + &file_name + "." + &get_random_string(6),
+    );
+    std::fs::write(&tmp, contents)?;
+    if let Err(x) = std::fs::rename(&tmp, path) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(x.into());
+    }
+    Ok(())
+}
+
+/// Splits the packages up by the first `prefix_length` characters of their name
+pub fn split_into_shards(
+    packages: &[OnlinePackage],
+    prefix_length: usize,
+) -> BTreeMap<String, RepositoryIndex> {
+    let mut ret = BTreeMap::<String, RepositoryIndex>::new();
+    for pkg in packages {
+        ret.entry(shard_prefix(&pkg.name, prefix_length))
+            .or_insert(RepositoryIndex { packages: vec![] })
+            .packages
+            .push(pkg.clone());
+    }
+    ret
+}
+
+/// Serializes and compresses an index
+fn compress_index(index: &RepositoryIndex) -> Result<Vec<u8>> {
+    let doc =
+        ron::ser::to_string_pretty(index, ron::ser::PrettyConfig::default())?;
+    Ok(zstd::encode_all(doc.as_bytes(), 19)?)
+}
+
+/// Writes the shards of the index into `${root}/index`, along with the shard
+/// manifest. Shards left over from earlier runs are removed.
+fn write_shards(
+    root: &Path,
+    packages: &[OnlinePackage],
+    prefix_length: usize,
+) -> Result<()> {
+    let shard_dir = root.join("index");
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .create(&shard_dir)?;
+
+    let shards = split_into_shards(packages, prefix_length);
+    for (prefix, shard) in &shards {
+        write_atomically(
+            &shard_dir.join(prefix.clone() + ".ron.zst"),
+            &compress_index(shard)?,
+        )?;
+    }
+    let manifest = ShardManifest {
+        prefix_length,
+        shards: shards.keys().cloned().collect(),
+    };
+    write_atomically(
+        &root.join(SHARD_MANIFEST),
+        ron::ser::to_string_pretty(
+            &manifest,
+            ron::ser::PrettyConfig::default(),
+        )?
+        .as_bytes(),
+    )?;
+    remove_stale_shards(root, &manifest.shards)
+}
+
+/// Removes shard files that aren't part of `keep`
+fn remove_stale_shards(root: &Path, keep: &[String]) -> Result<()> {
+    let Ok(entries) = std::fs::read_dir(root.join("index")) else {
+        return Ok(());
+    };
+    for ent in entries {
+        let path = ent?.path();
+        let name = path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Some(prefix) = name.strip_suffix(".ron.zst") {
+            if !keep.iter().any(|x| x == prefix) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Generates `index.ron`, `index.ron.zst` and `sonames.ron` for the
+/// repository at `root`. If `shard_prefix_length` is given, the index is also
+/// split into shards by the start of the package names.
+pub fn generate_index(
+    root: &Path,
+    shard_prefix_length: Option<usize>,
+) -> Result<()> {
+    let index_path = root.join("index.ron");
+    let previous: HashMap<String, OnlinePackage> =
+        match std::fs::read_to_string(&index_path) {
+            Ok(x) => match ron::from_str::<RepositoryIndex>(&x) {
+                Ok(x) => {
+                    x.packages.into_iter().map(|x| (x.url.clone(), x)).collect()
+                }
+                Err(x) => {
+                    warn!("Failed to parse the existing index.ron: {x}");
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+    let cache_path = root.join(INDEX_CACHE);
+    let cache: HashMap<String, CachedFile> =
+        std::fs::read_to_string(&cache_path)
+            .ok()
+            .and_then(|x| ron::from_str(&x).ok())
+            .unwrap_or_default();
+
+    let dpts = find_packages(root);
+
+    let pb = ProgressBar::new(dpts.len() as u64);
+    pb.set_style(
+        indicatif::ProgressStyle::default_bar()
+            .template(crate::PROGRESS_STYLE)?
+            .progress_chars(crate::PROGRESS_CHARS),
+    );
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::<(
+        usize,
+        Result<Option<(OnlinePackage, CachedFile)>>,
+    )>::new());
+    let workers = std::thread::available_parallelism()
+        .map(|x| x.get())
+        .unwrap_or(1)
+        .min(dpts.len().max(1));
+    std::thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(path) = dpts.get(i) else {
+                    break;
+                };
+                let r = index_package(root, path, &previous, &cache);
+                results.lock().unwrap().push((i, r));
+                pb.inc(1);
+            });
+        }
+    });
+    pb.finish();
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+
+    let mut out = RepositoryIndex { packages: vec![] };
+    let mut new_cache = BTreeMap::<String, CachedFile>::new();
+    for (_, r) in results {
+        if let Some((pkg, file)) = r? {
+            new_cache.insert(pkg.url.clone(), file);
+            out.packages.push(pkg);
+        }
+    }
+
+    check_index(&out.packages)?;
+
+    let mut sonames = SonameIndex::default();
+    for pkg in &out.packages {
+        for lib in &pkg.provides {
+            let providers = sonames.sonames.entry(lib.clone()).or_default();
+            if !providers.contains(&pkg.name) {
+                providers.push(pkg.name.clone());
+            }
+        }
+    }
+
+    match shard_prefix_length {
+        Some(x) => write_shards(root, &out.packages, x)?,
+        None => {
+            // Make sure clients don't pick up shards from an earlier run
+            if root.join(SHARD_MANIFEST).exists() {
+                std::fs::remove_file(root.join(SHARD_MANIFEST))?;
+            }
+            remove_stale_shards(root, &[])?;
+        }
+    }
+    write_atomically(&root.join("index.ron.zst"), &compress_index(&out)?)?;
+    write_atomically(
+        &index_path,
+        ron::ser::to_string_pretty(&out, ron::ser::PrettyConfig::default())?
+            .as_bytes(),
+    )?;
+    write_atomically(
+        &root.join("sonames.ron"),
+        ron::ser::to_string_pretty(
+            &sonames,
+            ron::ser::PrettyConfig::default(),
+        )?
+        .as_bytes(),
+    )?;
+    write_atomically(
+        &cache_path,
+        ron::ser::to_string_pretty(
+            &new_cache,
+            ron::ser::PrettyConfig::default(),
+        )?
+        .as_bytes(),
+    )?;
+    info!("Indexed {} packages", out.packages.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(name: &str, version: &str, url: &str) -> OnlinePackage {
+        OnlinePackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            url: url.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_index_1() {
+        check_index(&[
+            package("a", "1.0", "a-1.0.dpt"),
+            package("a", "1.1", "a-1.1.dpt"),
+            package("b", "1.0", "misnamed.dpt"),
+        ])
+        .unwrap();
+        check_index(&[
+            package("a", "1.0", "a-1.0.dpt"),
+            package("a", "1.0", "sub/a-1.0.dpt"),
+        ])
+        .expect_err("Duplicate packages were accepted");
+    }
+
+    #[test]
+    fn split_into_shards_1() {
+        let shards = split_into_shards(
+            &[
+                package("python", "3.12", "python-3.12.dpt"),
+                package("perl", "5.40", "perl-5.40.dpt"),
+                package("pyyaml", "6.0", "pyyaml-6.0.dpt"),
+                package("x", "1.0", "x-1.0.dpt"),
+            ],
+            2,
+        );
+        assert_eq!(
+            shards.keys().cloned().collect::<Vec<String>>(),
+            vec!["pe".to_string(), "py".to_string(), "x".to_string()]
+        );
+        assert_eq!(shards["py"].packages.len(), 2);
+    }
+
+    #[test]
+    fn index_package_reuse() {
+        let dir = std::env::temp_dir()
+            .join(String::from("dpt-index-test-") + &get_random_string(10));
+        std::fs::DirBuilder::new().create(&dir).unwrap();
+        // Not a real package, so it can only be indexed by reusing the entry
+        std::fs::write(dir.join("a-1.0.dpt"), "not a package").unwrap();
+        let metadata = std::fs::metadata(dir.join("a-1.0.dpt")).unwrap();
+
+        let mut old = package("a", "1.0", "a-1.0.dpt");
+        old.download_size = Some(metadata.len());
+        old.sha256 = Some("stale".into());
+        let mut cached = CachedFile {
+            size: metadata.len(),
+            mtime: mtime_nanos(&metadata),
+            sha256: "stale".into(),
+        };
+        let previous = HashMap::from([(old.url.clone(), old.clone())]);
+        let mut cache = HashMap::from([(old.url.clone(), cached.clone())]);
+        let index = |previous: &HashMap<String, OnlinePackage>,
+                     cache: &HashMap<String, CachedFile>| {
+            index_package(&dir, &dir.join("a-1.0.dpt"), previous, cache)
+        };
+        assert_eq!(
+            index(&previous, &cache).unwrap(),
+            Some((old.clone(), cached.clone()))
+        );
+
+        // Like a copy that kept an older modification time
+        cached.mtime -= 1;
+        cache.insert(old.url.clone(), cached.clone());
+        index(&previous, &cache).expect_err("A stale entry was reused");
+        index(&previous, &HashMap::new())
+            .expect_err("An entry was reused without the cache");
+
+        // The same contents are still found by their hash
+        let sha256 = sha256_file(&dir.join("a-1.0.dpt")).unwrap();
+        old.sha256 = Some(sha256.clone());
+        let previous = HashMap::from([(old.url.clone(), old.clone())]);
+        let (pkg, file) = index(&previous, &cache).unwrap().unwrap();
+        assert_eq!(pkg, old);
+        assert_eq!(file.mtime, mtime_nanos(&metadata));
+        assert_eq!(file.sha256, sha256);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn package_url_1() {
+        assert_eq!(
+            package_url(Path::new("."), Path::new("./sub/a-1.0.dpt")).unwrap(),
+            "sub/a-1.0.dpt"
+        );
+        assert_eq!(
+            package_url(Path::new("/repo"), Path::new("/repo/a-1.0.dpt"))
+                .unwrap(),
+            "a-1.0.dpt"
+        );
+    }
+}
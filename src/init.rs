@@ -0,0 +1,474 @@
+use std::{
+    collections::HashMap,
+    os::unix::{fs::MetadataExt, process::CommandExt},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info, warn};
+use nix::{
+    errno::Errno,
+    mount::{umount2, MntFlags, MsFlags},
+    sys::{
+        reboot::{reboot, set_cad_enabled, RebootMode},
+        signal::{kill, sigprocmask, SigSet, SigmaskHow, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{sethostname, setsid, sync, Pid},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{dpt_file::read_dpt_lock_file, signals};
+
+/// How long programs get to exit on shutdown by default, in seconds
+const SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// A filesystem that is mounted when dpt starts as PID 1
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InitMount {
+    pub source: String,
+    pub target: PathBuf,
+    pub fstype: String,
+    /// Comma separated, like `nosuid,mode=755`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub options: String,
+}
+
+impl InitMount {
+    fn new(source: &str, target: &str, fstype: &str) -> InitMount {
+        InitMount {
+            source: source.to_string(),
+            target: PathBuf::from(target),
+            fstype: fstype.to_string(),
+            options: String::new(),
+        }
+    }
+}
+
+/// A program that the built-in init starts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Service {
+    pub name: String,
+    /// A binary of an installed package or an absolute path, followed by its
+    /// arguments
+    pub command: Vec<String>,
+    /// Start it again when it exits
+    #[serde(default)]
+    pub restart: bool,
+}
+
+/// The `init` field of `dpt.ron`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InitConfig {
+    /// Replaces the default mounts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mounts: Option<Vec<InitMount>>,
+    /// Defaults to the contents of `/etc/hostname`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// A program, like another init, to run instead of `services`. dpt stays
+    /// PID 1 to reap orphans, and shuts down once it exits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exec: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<Service>,
+    /// How long programs get to exit on shutdown, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shutdown_timeout: Option<u64>,
+}
+
+pub fn get_init_config() -> InitConfig {
+    read_dpt_lock_file()
+        .ok()
+        .and_then(|x| x.init)
+        .unwrap_or_default()
+}
+
+fn default_mounts() -> Vec<InitMount> {
+    vec![
+        InitMount::new("proc", "/proc", "proc"),
+        InitMount::new("tmpfs", "/tmp", "tmpfs"),
+        InitMount::new("sys", "/sys", "sysfs"),
+        InitMount::new("cgroup2", "/sys/fs/cgroup", "cgroup2"),
+        InitMount::new("devpts", "/dev/pts", "devpts"),
+    ]
+}
+
+/// Splits mount options into flags and the options for the filesystem
+fn parse_mount_options(options: &str) -> (MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+    for x in options.split(',').filter(|x| !x.is_empty()) {
+        match x {
+            "ro" => flags |= MsFlags::MS_RDONLY,
+            "nosuid" => flags |= MsFlags::MS_NOSUID,
+            "nodev" => flags |= MsFlags::MS_NODEV,
+            "noexec" => flags |= MsFlags::MS_NOEXEC,
+            "noatime" => flags |= MsFlags::MS_NOATIME,
+            "relatime" => flags |= MsFlags::MS_RELATIME,
+            x => data.push(x),
+        }
+    }
+    (flags, data.join(","))
+}
+
+/// Whether something is mounted on `path`
+fn is_mount_point(path: &Path) -> Result<bool> {
+    let parent = path.join("..");
+    Ok(
+        std::fs::metadata(path)?.dev() != std::fs::metadata(parent)?.dev()
+            || path == Path::new("/"),
+    )
+}
+
+fn mount(m: &InitMount) -> Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .create(&m.target)?;
+    if is_mount_point(&m.target)? {
+        return Ok(()); // Already set up before PID 1 was handed over
+    }
+    let (flags, data) = parse_mount_options(&m.options);
+    nix::mount::mount(
+        Some(m.source.as_str()),
+        &m.target,
+        Some(m.fstype.as_str()),
+        flags,
+        if data.is_empty() {
+            None
+        } else {
+            Some(data.as_str())
+        },
+    )?;
+    Ok(())
+}
+
+/// Mounts the filesystems and sets the hostname. Failures are only logged,
+/// since PID 1 exiting takes the whole system down.
+pub fn setup(config: &InitConfig) {
+    for m in config.mounts.clone().unwrap_or_else(default_mounts) {
+        if let Err(x) = mount(&m) {
+            error!("Failed to mount {}!: {x}", m.target.display());
+        }
+    }
+
+    let hostname = config.hostname.clone().or_else(|| {
+        std::fs::read_to_string("/etc/hostname")
+            .ok()
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+    });
+    if let Some(hostname) = hostname {
+        if let Err(x) = sethostname(&hostname) {
+            error!("Failed to set the hostname to {hostname}!: {x}");
+        }
+    }
+}
+
+/// Makes a command from a service's `command`. Package binaries are run
+/// through dpt, which finds them by the name it is called as.
+fn command(cmd: &[String]) -> Result<Command> {
+    let (first, args) = cmd.split_first().ok_or(anyhow!("Empty command"))?;
+    let mut ret = if first.contains('/') {
+        Command::new(first)
+    } else {
+        let mut x = Command::new(
+            std::env::current_exe().context("Failed to find dpt")?,
+        );
+        x.arg0(first);
+        x
+    };
+    ret.args(args);
+    Ok(ret)
+}
+
+fn start(service: &Service) -> Option<Pid> {
+    let mut cmd = match command(&service.command) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Failed to start {}: {x}", service.name);
+            return None;
+        }
+    };
+    unsafe {
+        cmd.pre_exec(|| {
+            signals::unblock_signals()?;
+            setsid()?;
+            Ok(())
+        });
+    }
+    match cmd.spawn() {
+        Ok(x) => {
+            info!("Started {}", service.name);
+            Some(Pid::from_raw(x.id() as i32))
+        }
+        Err(x) => {
+            error!("Failed to start {}: {x}", service.name);
+            None
+        }
+    }
+}
+
+/// The targets of every mount, in the order they were mounted
+fn mounted_targets() -> Vec<PathBuf> {
+    let Ok(mounts) = std::fs::read_to_string("/proc/self/mounts") else {
+        return Vec::new();
+    };
+    mounts
+        .lines()
+        .filter_map(|x| x.split(' ').nth(1))
+        // Spaces and such are escaped as octal
+        .map(|x| {
+            let mut ret = String::new();
+            let mut chars = x.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    ret.push(c);
+                    continue;
+                }
+                let code: String = chars.by_ref().take(3).collect();
+                match u8::from_str_radix(&code, 8) {
+                    Ok(x) => ret.push(x as char),
+                    Err(_) => ret.push_str(&code),
+                }
+            }
+            PathBuf::from(ret)
+        })
+        .collect()
+}
+
+/// Stops every process, first asking nicely, then syncs. On a machine every
+/// filesystem is unmounted and the root is made read-only afterwards.
+fn shutdown(timeout: Duration, machine: bool) {
+    info!("Stopping every process");
+    let _ = kill(Pid::from_raw(-1), Signal::SIGTERM);
+    let _ = kill(Pid::from_raw(-1), Signal::SIGCONT);
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Err(Errno::ECHILD) => break,
+            Ok(WaitStatus::StillAlive) => {
+                std::thread::sleep(Duration::from_millis(50))
+            }
+            _ => {}
+        }
+    }
+    if kill(Pid::from_raw(-1), Signal::SIGKILL).is_ok() {
+        warn!("Killed the programs that were still running");
+    }
+    while waitpid(None, None).is_ok() {}
+    sync();
+
+    if !machine {
+        return;
+    }
+    info!("Unmounting filesystems");
+    for target in mounted_targets().iter().rev() {
+        if target != Path::new("/") {
+            let _ = umount2(target, MntFlags::empty());
+        }
+    }
+    if let Err(x) = nix::mount::mount(
+        Option::<&str>::None,
+        "/",
+        Option::<&str>::None,
+        MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+        Option::<&str>::None,
+    ) {
+        warn!("Failed to remount / read-only: {x}");
+    }
+    sync();
+}
+
+/// Runs as the init of the system or of a PID namespace. Starts `exec` if it
+/// is set, and otherwise the services, reaps orphans and shuts down on
+/// `SIGINT` (reboot), `SIGTERM` or `SIGPWR` (power off), or once every one of
+/// them has exited.
+pub fn run(config: &InitConfig) -> Result<i32> {
+    if std::process::id() != 1 {
+        bail!("The init has to be PID 1");
+    }
+    info!("Starting the built-in init!");
+    // Ctrl-Alt-Del sends SIGINT instead of rebooting. This fails in PID
+    // namespaces, which have no machine to shut down.
+    let machine = set_cad_enabled(false).is_ok();
+    setup(config);
+
+    let mut set = SigSet::empty();
+    for sig in [
+        Signal::SIGCHLD,
+        Signal::SIGINT,
+        Signal::SIGTERM,
+        Signal::SIGPWR,
+    ] {
+        set.add(sig);
+    }
+    sigprocmask(SigmaskHow::SIG_BLOCK, Some(&set), None)?;
+
+    let init = config.exec.as_ref().map(|x| Service {
+        name: x.first().cloned().unwrap_or_default(),
+        command: x.clone(),
+        restart: false,
+    });
+    let mut running = HashMap::<Pid, &Service>::new();
+    if let Some(init) = &init {
+        if let Some(pid) = start(init) {
+            running.insert(pid, init);
+        }
+    }
+    // Without an init the services are better than a kernel panic
+    if running.is_empty() {
+        for service in &config.services {
+            if let Some(pid) = start(service) {
+                running.insert(pid, service);
+            }
+        }
+    }
+
+    let mode = loop {
+        if running.is_empty() {
+            info!("No services are left running");
+            break RebootMode::RB_POWER_OFF;
+        }
+        match set.wait()? {
+            Signal::SIGINT => break RebootMode::RB_AUTOBOOT,
+            Signal::SIGTERM | Signal::SIGPWR => break RebootMode::RB_POWER_OFF,
+            _ => {}
+        }
+        // Orphans are reaped along with the services
+        loop {
+            let status = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) | Err(_) => break,
+                Ok(x) => x,
+            };
+            let Some(service) = status.pid().and_then(|x| running.remove(&x))
+            else {
+                continue;
+            };
+            match signals::exit_code(status) {
+                Some(0) => info!("{} exited", service.name),
+                Some(x) => warn!("{} exited with {x}", service.name),
+                None => continue,
+            }
+            if service.restart {
+                // Don't spin on a service that can't start
+                std::thread::sleep(Duration::from_secs(1));
+                if let Some(pid) = start(service) {
+                    running.insert(pid, service);
+                }
+            }
+        }
+    };
+
+    shutdown(
+        Duration::from_secs(
+            config.shutdown_timeout.unwrap_or(SHUTDOWN_TIMEOUT),
+        ),
+        machine,
+    );
+    if machine {
+        let Err(x) = reboot(mode);
+        error!("Failed to shut down!: {x}");
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dpt_file::parse_dpt_file, run::get_random_string};
+    use nix::{
+        sched::{unshare, CloneFlags},
+        unistd::{fork, ForkResult},
+    };
+
+    #[test]
+    fn mount_options() {
+        assert_eq!(
+            parse_mount_options("nosuid,mode=755,nodev,size=10M"),
+            (
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                "mode=755,size=10M".into()
+            )
+        );
+        assert_eq!(parse_mount_options(""), (MsFlags::empty(), "".into()));
+    }
+
+    #[test]
+    fn init_in_dpt_ron() {
+        let doc = r#"
+(
+    packages: [],
+    users: [],
+    groups: [],
+    init: (
+        hostname: "box",
+        mounts: [(source: "tmpfs", target: "/run", fstype: "tmpfs", options: "mode=755")],
+        services: [(name: "getty", command: ["agetty", "tty1"], restart: true)],
+    ),
+)
+        "#;
+        let init = parse_dpt_file(doc).unwrap().init.unwrap();
+        assert_eq!(init.hostname, Some("box".into()));
+        assert_eq!(init.mounts.unwrap()[0].options, "mode=755");
+        assert_eq!(
+            init.services,
+            vec![Service {
+                name: "getty".into(),
+                command: vec!["agetty".into(), "tty1".into()],
+                restart: true,
+            }]
+        );
+        assert_eq!(init.exec, None);
+    }
+
+    /// Exit code of the test's child when it can't make a PID namespace
+    #[test]
+    #[ignore = "needs root, run with --ignored"]
+    fn run_in_pid_namespace() {
+        let out = std::env::temp_dir()
+            .join(String::from("dpt-init-test-") + &get_random_string(10));
+        let config = InitConfig {
+            mounts: Some(Vec::new()),
+            hostname: Some("dpt-test".into()),
+            exec: Some(vec![
+                "/bin/sh".into(),
+                "-c".into(),
+                format!("echo $$ $PPID > {}", out.display()),
+            ]),
+            services: Vec::new(),
+            shutdown_timeout: Some(1),
+        };
+        let wait = |pid| match waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, x)) => x,
+            x => panic!("Unexpected status {x:?}"),
+        };
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let flags = CloneFlags::CLONE_NEWPID
+                    | CloneFlags::CLONE_NEWNS
+                    | CloneFlags::CLONE_NEWUTS;
+                if let Err(x) = unshare(flags) {
+                    eprintln!("Failed to unshare: {x}");
+                    std::process::exit(1);
+                }
+                let code = match unsafe { fork() } {
+                    Ok(ForkResult::Child) => run(&config).unwrap_or(1),
+                    Ok(ForkResult::Parent { child }) => wait(child),
+                    Err(_) => 1,
+                };
+                std::process::exit(code);
+            }
+            ForkResult::Parent { child } => {
+                assert_eq!(wait(child), 0);
+                let ids = std::fs::read_to_string(&out).unwrap();
+                std::fs::remove_file(&out).unwrap();
+                // A child of dpt, which is still PID 1
+                assert_eq!(ids.split_whitespace().nth(1), Some("1"));
+                assert_ne!(ids.split_whitespace().next(), Some("1"));
+            }
+        }
+    }
+}
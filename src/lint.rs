@@ -0,0 +1,482 @@
+use std::{
+    collections::HashSet,
+    fmt::{self, Display},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use walkdir::WalkDir;
+
+use crate::{
+    bin_index::BIN_DIRS,
+    elf::{read_elf, ElfInfo},
+    envvars::is_unsafe_var,
+    pkg::{
+        decompress_pkg_read, get_package_config, string_to_package, Glue,
+        PackageConfig, Version,
+    },
+    repo::{get_all_available_packages, parse_version_range, OnlinePackage},
+    run::{get_random_string, join_proper},
+    scan::library_names,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LintIssue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl LintIssue {
+    pub(crate) fn error(message: String) -> Self {
+        LintIssue {
+            severity: Severity::Error,
+            message,
+        }
+    }
+
+    pub(crate) fn warning(message: String) -> Self {
+        LintIssue {
+            severity: Severity::Warning,
+            message,
+        }
+    }
+}
+
+impl Display for LintIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Lints either a package directory or a `.dpt` file
+pub fn lint_path(
+    path: &Path,
+    installed: &[OnlinePackage],
+) -> Result<Vec<LintIssue>> {
+    if path.is_dir() {
+        return lint_directory(path, installed);
+    }
+
+    let tmp_dir = std::env::temp_dir()
+        .join(String::from("dpt-lint-") + &get_random_string(10));
+    let mut archive = decompress_pkg_read(
+        std::fs::File::open(path)
+            .context(format!("Failed to open {}", path.display()))?,
+    )?;
+    archive.set_preserve_ownerships(false);
+    let ret = match archive.unpack(&tmp_dir) {
+        Ok(()) => lint_directory(&tmp_dir, installed),
+        Err(x) => Err(x.into()),
+    };
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir)?;
+    }
+    ret
+}
+
+/// Checks the package configuration on its own, without looking at any files
+pub fn lint_package_config(cfg: &PackageConfig) -> Vec<LintIssue> {
+    let mut issues = Vec::<LintIssue>::new();
+
+    if cfg.name.is_empty() {
+        issues.push(LintIssue::error("The package name is empty!".into()));
+    }
+    if let Err(x) = Version::from_str(&cfg.version) {
+        issues.push(LintIssue::error(format!(
+            "Invalid package version '{}': {x}",
+            cfg.version
+        )));
+    }
+    let store_name = cfg.name.clone() + "-" + &cfg.version;
+    match string_to_package(&store_name) {
+        Ok(x) if x.name == cfg.name && x.version == cfg.version => {}
+        Ok(x) => issues.push(LintIssue::error(format!(
+            "'{store_name}' would be read back as name '{}' and version '{}'!",
+            x.name, x.version
+        ))),
+        Err(_) => issues.push(LintIssue::error(format!(
+            "'{store_name}' can't be split into a name and version!"
+        ))),
+    }
+
+    for dep in &cfg.depends {
+        if dep.name.is_empty() {
+            issues.push(LintIssue::error("A dependency has no name!".into()));
+        }
+        if let Err(x) = parse_version_range(&dep.version) {
+            issues.push(LintIssue::error(format!(
+                "Invalid version range '{}' for dependency '{}': {x}",
+                dep.version, dep.name
+            )));
+        }
+    }
+
+    for glue in &cfg.glue {
+        if let Glue::Glob(globs) = glue {
+            for g in globs {
+                if let Err(x) = glob::Pattern::new(g) {
+                    issues.push(LintIssue::error(format!(
+                        "Invalid glob '{g}' in glue: {x}"
+                    )));
+                }
+            }
+        }
+    }
+
+    for g in &cfg.overrides {
+        if let Err(x) = glob::Pattern::new(g) {
+            issues.push(LintIssue::error(format!(
+                "Invalid glob '{g}' in overrides: {x}"
+            )));
+        }
+    }
+
+    for name in cfg.env.keys() {
+        if is_unsafe_var(name) {
+            issues.push(LintIssue::error(format!(
+                "Variable {name} in env can't be set by a package!"
+            )));
+        }
+    }
+
+    if let Some(limits) = &cfg.limits {
+        if let Err(x) = limits.check() {
+            issues.push(LintIssue::error(format!("Invalid limits: {x}")));
+        }
+    }
+
+    issues
+}
+
+/// Lints an unpacked package
+pub fn lint_directory(
+    dir: &Path,
+    installed: &[OnlinePackage],
+) -> Result<Vec<LintIssue>> {
+    let mut issues = Vec::<LintIssue>::new();
+
+    let cfg = match std::fs::read_to_string(dir.join("dpt/pkg.ron")) {
+        Ok(x) => match get_package_config(&x) {
+            Ok(x) => Some(x),
+            Err(x) => {
+                issues.push(LintIssue::error(format!(
+                    "Failed to parse dpt/pkg.ron: {x}"
+                )));
+                None
+            }
+        },
+        Err(x) => {
+            issues.push(LintIssue::error(format!(
+                "Failed to read dpt/pkg.ron: {x}"
+            )));
+            None
+        }
+    };
+
+    if let Some(cfg) = &cfg {
+        issues.append(&mut lint_package_config(cfg));
+    }
+
+    if dir.join("dpt/.done").exists() {
+        issues.push(LintIssue::error(
+            "dpt/.done exists! It is created on install and must not be shipped."
+                .into(),
+        ));
+    }
+
+    let mut elfs = Vec::<(PathBuf, ElfInfo)>::new();
+    for ent in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+        let rel = ent.path().strip_prefix(dir)?;
+        if rel.as_os_str().is_empty() || ent.path_is_symlink() {
+            continue;
+        }
+        let mode = ent.metadata()?.permissions().mode();
+        if mode & 0o002 != 0
+            && !(ent.file_type().is_dir() && mode & 0o1000 != 0)
+        {
+            issues.push(LintIssue::error(format!(
+                "/{} is world-writable!",
+                rel.display()
+            )));
+        }
+        if mode & 0o4000 != 0
+            || (mode & 0o2000 != 0 && !ent.file_type().is_dir())
+        {
+            issues.push(LintIssue::warning(format!(
+                "/{} is setuid or setgid!",
+                rel.display()
+            )));
+        }
+        if !ent.file_type().is_file() {
+            continue;
+        }
+
+        if mode & 0o111 != 0 {
+            let parent = rel.parent().unwrap_or(Path::new(""));
+            let in_bin_dir =
+                parent.ends_with("bin") || parent.ends_with("sbin");
+            if in_bin_dir && !BIN_DIRS.iter().any(|x| parent == Path::new(x)) {
+                issues.push(LintIssue::warning(format!(
+                    "/{} will not be found by `dpt run`, since it isn't in /usr/bin or /bin!",
+                    rel.display()
+                )));
+            }
+        }
+
+        match read_elf(ent.path()) {
+            Ok(Some(x)) => elfs.push((rel.to_path_buf(), x)),
+            Ok(None) => {}
+            Err(x) => issues.push(LintIssue::warning(format!(
+                "Failed to read ELF file /{}: {x}",
+                rel.display()
+            ))),
+        }
+    }
+
+    if let Some(cfg) = &cfg {
+        let mut dirs = vec![dir.to_path_buf()];
+        let complete =
+            lint_dependencies(cfg, installed, &mut dirs, &mut issues);
+        issues.append(&mut lint_elf_dependencies(&dirs, &elfs, complete));
+    }
+
+    Ok(issues)
+}
+
+/// Looks up the (transitive) dependencies of the package in the store, adding
+/// their directories to `dirs`. Returns false if any of them isn't installed.
+fn lint_dependencies(
+    cfg: &PackageConfig,
+    installed: &[OnlinePackage],
+    dirs: &mut Vec<PathBuf>,
+    issues: &mut Vec<LintIssue>,
+) -> bool {
+    let mut complete = true;
+
+    let mut seen = HashSet::<String>::new();
+    let mut queue = cfg.depends.clone();
+    let mut available: Option<Vec<OnlinePackage>> = None;
+    while let Some(dep) = queue.pop() {
+        if !seen.insert(dep.name.clone()) {
+            continue;
+        }
+        let range = match parse_version_range(&dep.version) {
+            Ok(x) => x,
+            Err(_) => continue, // Already reported by lint_package_config
+        };
+        let found = installed
+            .iter()
+            .filter(|x| x.name == dep.name)
+            .filter(|x| {
+                Version::from_str(&x.version).is_ok_and(|v| range.contains(&v))
+            })
+            .max_by(|a, b| {
+                Version::from_str(&a.version)
+                    .unwrap()
+                    .cmp(&Version::from_str(&b.version).unwrap())
+            });
+        match found {
+            Some(x) => {
+                dirs.push(PathBuf::from(&x.url));
+                queue.append(&mut x.depends.clone());
+            }
+            None => {
+                complete = false;
+                if available.is_none() {
+                    available =
+                        Some(get_all_available_packages().unwrap_or_default());
+                }
+                if available
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .any(|x| x.name == dep.name)
+                {
+                    issues.push(LintIssue::warning(format!(
+                        "Dependency '{}' is not in the store, so the libraries it provides can't be checked",
+                        dep.name
+                    )));
+                } else {
+                    issues.push(LintIssue::error(format!(
+                        "Dependency '{}' does not exist in the store or any repository!",
+                        dep.name
+                    )));
+                }
+            }
+        }
+    }
+
+    complete
+}
+
+/// Checks that every interpreter and `DT_NEEDED` library is shipped by one
+/// of `dirs`, which are the package itself and its dependencies.
+fn lint_elf_dependencies(
+    dirs: &[PathBuf],
+    elfs: &[(PathBuf, ElfInfo)],
+    complete: bool,
+) -> Vec<LintIssue> {
+    let mut issues = Vec::<LintIssue>::new();
+    if elfs
+        .iter()
+        .all(|(_, x)| x.interpreter.is_none() && x.needed.is_empty())
+    {
+        return issues;
+    }
+
+    let mut provided = HashSet::<String>::new();
+    for d in dirs {
+        provided.extend(library_names(d));
+    }
+
+    let severity = if complete {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    for (path, info) in elfs {
+        if let Some(interp) = &info.interpreter {
+            let interp_path = Path::new(interp);
+            let name = interp_path
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let exists = dirs
+                .iter()
+                .any(|d| join_proper(d, interp_path).is_ok_and(|x| x.exists()));
+            if !exists && !provided.contains(&name) {
+                issues.push(LintIssue {
+                    severity,
+                    message: format!(
+                        "/{} uses the interpreter {interp}, which no dependency provides!",
+                        path.display()
+                    ),
+                });
+            }
+        }
+        for lib in &info.needed {
+            if !provided.contains(lib) {
+                issues.push(LintIssue {
+                    severity,
+                    message: format!(
+                        "/{} needs {lib}, which no dependency provides!",
+                        path.display()
+                    ),
+                });
+            }
+        }
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg::Dependency;
+
+    fn config(name: &str, version: &str) -> PackageConfig {
+        PackageConfig {
+            name: name.to_string(),
+            version: version.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn lint_package_config_clean() {
+        assert_eq!(lint_package_config(&config("a-b-c", "1.2.3")), vec![]);
+    }
+
+    #[test]
+    fn lint_package_config_split() {
+        let issues = lint_package_config(&config("python", "3.12-1"));
+        assert_eq!(issues.len(), 2);
+        assert!(issues.iter().all(|x| x.severity == Severity::Error));
+    }
+
+    #[test]
+    fn lint_package_config_ranges() {
+        let mut cfg = config("abc", "1.0");
+        cfg.depends = vec![
+            Dependency {
+                name: "good".to_string(),
+                version: ">=1.0".to_string(),
+            },
+            Dependency {
+                name: "bad".to_string(),
+                version: "^1.0".to_string(),
+            },
+            Dependency {
+                name: "also-bad".to_string(),
+                version: ">".to_string(),
+            },
+        ];
+        cfg.glue = vec![Glue::Glob(vec!["/usr/lib/[".to_string()])];
+        cfg.env = [("LD_PRELOAD".to_string(), "/tmp/a.so".to_string())].into();
+        let issues = lint_package_config(&cfg);
+        assert_eq!(issues.len(), 4);
+    }
+
+    #[test]
+    fn lint_directory_1() {
+        let dir = std::env::temp_dir()
+            .join(String::from("dpt-lint-test-") + &get_random_string(10));
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(dir.join("dpt"))
+            .unwrap();
+        for x in ["usr/local/bin", "usr/sbin"] {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .create(dir.join(x))
+                .unwrap();
+        }
+        std::fs::write(
+            dir.join("dpt/pkg.ron"),
+            r#"(name: "abc", version: "1.0", depends: [], glue: [])"#,
+        )
+        .unwrap();
+        std::fs::write(dir.join("dpt/.done"), "").unwrap();
+        std::fs::write(dir.join("usr/local/bin/abc"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(
+            dir.join("usr/local/bin/abc"),
+            std::fs::Permissions::from_mode(0o757),
+        )
+        .unwrap();
+        std::fs::write(dir.join("usr/sbin/abcd"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(
+            dir.join("usr/sbin/abcd"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+
+        let issues = lint_directory(&dir, &[]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            issues
+                .iter()
+                .filter(|x| x.severity == Severity::Error)
+                .count(),
+            2
+        );
+        assert_eq!(
+            issues
+                .iter()
+                .filter(|x| x.severity == Severity::Warning)
+                .count(),
+            2
+        );
+        assert!(issues.iter().any(|x| x
+            .message
+            .starts_with("/usr/sbin/abcd will not be found")));
+    }
+}
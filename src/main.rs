@@ -5,13 +5,29 @@
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
 mod base;
+mod bin_index;
+mod binds;
+mod cgroup;
 mod config;
+mod creds;
 mod dpt_file;
+mod elf;
 mod env;
+mod envvars;
+mod hash;
+mod index;
+mod init;
+mod lint;
+mod named_env;
 mod pkg;
+mod project_env;
 mod repo;
 mod run;
+mod sandbox;
+mod scan;
+mod signals;
 mod store;
+mod user_profile;
 
 pub const PROGRESS_STYLE_BYTES: &str =
     "{msg} [{wide_bar:.green/blue}] {bytes}/{total_bytes} ({eta})";
@@ -23,38 +39,38 @@ use log::info;
 use nix::{
     mount::MsFlags,
     sched::{unshare, CloneFlags},
+    unistd::Pid,
 };
 use std::{
     fs::write,
-    io::Read,
     os::unix::process::CommandExt,
     path::{Path, PathBuf},
     process::exit,
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::Arc,
 };
 use sys_mount::{unmount, UnmountFlags};
 
 use base::rebuild_base;
-use dpt_file::read_dpt_file;
-use indicatif::ProgressIterator;
+use dpt_file::{read_dpt_file, read_dpt_lock_file, DptFile};
 
 use anyhow::{anyhow, bail, Context, Result};
 use colog::format::CologStyle;
 use log::{error, warn, Level};
-use pkg::{
-    decompress_pkg_read, get_package_config, string_to_package, Package,
-};
+use pkg::{get_package_config, string_to_package, Package};
 use repo::{
-    get_all_available_packages, install_pkgs_and_dependencies,
-    newest_package_from_name, package_to_onlinepackage, OnlinePackage,
-    RepositoryIndex,
+    get_available_packages_for, get_soname_index,
+    install_pkgs_and_dependencies, newest_package_from_name,
+    package_to_onlinepackage, OnlinePackage,
+};
+use run::{run_multiple_packages, RunOptions};
+use store::{
+    get_dpt_dir, get_installed_packages,
+    get_installed_packages_without_dpt_file, get_invoking_uid, is_rootless,
 };
-use run::run_multiple_packages;
-use store::{get_dpt_dir, get_installed_packages, get_package_for_bin};
 use uzers::{
-    self, get_current_uid, get_effective_uid,
-    switch::{set_current_uid, set_effective_uid},
+    self, get_current_uid,
+    switch::{set_current_uid, set_effective_uid, switch_user_group},
 };
 
 pub struct CustomLevelToken;
@@ -73,6 +89,13 @@ impl CologStyle for CustomLevelToken {
 }
 
 fn main() -> Result<()> {
+    // This also decides rootless mode before any command changes the
+    // effective uid, and remembers the user before it changes the real one
+    get_invoking_uid();
+    if !is_rootless() {
+        envvars::strip_unsafe_vars();
+    }
+
     let mut builder = colog::basic_builder();
     builder.format(colog::formatter(CustomLevelToken));
     if cfg!(debug_assertions) {
@@ -87,11 +110,6 @@ fn main() -> Result<()> {
     let args = std::env::args().collect::<Vec<String>>();
     let argc = std::env::args().count();
 
-    if get_effective_uid() != 0 {
-        error!("dpt needs to be installed setuid or run as root!");
-        exit(exitcode::USAGE);
-    }
-
     let me = args[0]
         .split("/")
         .last()
@@ -109,65 +127,13 @@ fn main() -> Result<()> {
             )
     {
         let packages = get_installed_packages()?;
-        let pkg = get_package_for_bin(me, &packages)?;
+        let pkg = bin_index::get_package_for_bin(me, &packages)?;
         let uid = get_current_uid();
-        set_current_uid(0)?;
+        become_root()?;
         let replace_current_process = if std::process::id() == 1 && uid == 0 {
             info!("DPT loaded!");
             info!("Starting init process!");
-            match nix::mount::mount(
-                Some("proc"),
-                "/proc",
-                Some("proc"),
-                MsFlags::empty(),
-                None::<&str>,
-            ) {
-                Ok(()) => {}
-                Err(x) => bail!("Failed to mount /proc!: {x}"),
-            }
-            match nix::mount::mount(
-                Some("tmpfs"),
-                "/tmp",
-                Some("tmpfs"),
-                MsFlags::empty(),
-                None::<&str>,
-            ) {
-                Ok(()) => {}
-                Err(x) => bail!("Failed to mount /tmpfs!: {x}"),
-            }
-            match nix::mount::mount(
-                Some("sys"),
-                "/sys",
-                Some("sysfs"),
-                MsFlags::empty(),
-                None::<&str>,
-            ) {
-                Ok(()) => {}
-                Err(x) => bail!("Failed to mount /sys!: {x}"),
-            }
-            match nix::mount::mount(
-                Some("cgroup2"),
-                "/sys/fs/cgroup",
-                Some("cgroup2"),
-                MsFlags::empty(),
-                None::<&str>,
-            ) {
-                Ok(()) => {}
-                Err(x) => bail!("Failed to mount /sys/fs/cgroup!: {x}"),
-            }
-            std::fs::DirBuilder::new()
-                .recursive(true)
-                .create("/dev/pts")?;
-            match nix::mount::mount(
-                Some("devpts"),
-                "/dev/pts",
-                Some("devpts"),
-                MsFlags::empty(),
-                None::<&str>,
-            ) {
-                Ok(()) => {}
-                Err(x) => bail!("Failed to mount /dev/pts!: {x}"),
-            }
+            init::setup(&init::get_init_config());
             true
         } else {
             false
@@ -179,9 +145,15 @@ fn main() -> Result<()> {
             Some(me),
             false,
             replace_current_process,
+            &RunOptions::default(),
         )?);
     }
 
+    // Started by the kernel, e.g. with init=/dpt/dpt
+    if argc < 2 && std::process::id() == 1 {
+        exit(init::run(&init::get_init_config())?);
+    }
+
     if argc < 2 {
         error!("Not enough arguments!");
         print_help();
@@ -190,8 +162,11 @@ fn main() -> Result<()> {
 
     if args[1] != "run-pkg-second-stage-not-intended-for-interactive-use"
         && args[1] != "run"
+        && args[1] != "exec"
         && args[1] != "run-multi"
         && args[1] != "dev-env"
+        && args[1] != "env"
+        && args[1] != "shell"
     {
         for arg in &args {
             match arg.as_str() {
@@ -211,8 +186,14 @@ fn main() -> Result<()> {
     match &args.get(1).unwrap() as &str {
         "rebuild" => {
             command_requires_root_uid();
-            let dpt = read_dpt_file()?;
-            let repo_packages = get_all_available_packages()?;
+            let dpt =
+                read_dpt_file(take_profile_option(&args[2..])?.as_deref())?;
+            let repo_packages = get_available_packages_for(
+                &dpt.packages
+                    .iter()
+                    .map(|x| x.name.clone())
+                    .collect::<Vec<String>>(),
+            )?;
 
             let done_list = install_pkgs_and_dependencies(
                 &dpt.packages
@@ -231,6 +212,8 @@ fn main() -> Result<()> {
             )?;
 
             rebuild_base(&dpt).context("Failed to build base!")?;
+            env::prune_environment_cache()
+                .context("Failed to prune cached environments!")?;
 
             let mut dpt_lock = dpt.clone();
 
@@ -250,29 +233,211 @@ fn main() -> Result<()> {
                 )?,
             )
             .context("Failed to write dpt.lock file")?;
+
+            let installed = get_installed_packages()?;
+            let bins =
+                bin_index::build_bin_index(&dpt_lock.packages, &installed)?;
+            bin_index::write_bin_index(
+                &bins,
+                &bin_index::get_bin_index_location(),
+            )?;
+            bin_index::update_launchers(
+                &bins,
+                &env::get_dpt_binary()?,
+                &bin_index::get_launcher_dir(),
+            )
+            .context("Failed to update the launchers!")?;
+            bin_index::warn_about_bins(
+                &bins,
+                &bin_index::get_launcher_dir(),
+                &dpt.providers,
+            );
+            for (pkg, conflicts) in env::conflicts_for_packages(
+                &dpt_file_packages(&dpt, &installed)?,
+                &installed,
+            )? {
+                for x in conflicts.iter().filter(|x| !x.intentional) {
+                    warn!("{}-{}: File conflict {x}", pkg.name, pkg.version);
+                }
+            }
+        }
+        "user" => match args.get(2).map(|x| x.as_str()) {
+            Some("rebuild") => {
+                let uid = get_current_uid();
+                let path = user_profile::get_user_dpt_file_location();
+                // It belongs to the user, so it is read as them
+                let file = {
+                    let _user =
+                        switch_user_group(uid, uzers::get_current_gid())?;
+                    user_profile::read_user_dpt_file(&path)?
+                };
+                become_root()?;
+                let (_, bins) = user_profile::rebuild_profile(uid, &file)?;
+                let launchers = user_profile::get_profile_launcher_dir(uid);
+                bin_index::warn_about_bins(&bins, &launchers, &file.providers);
+                info!("The launchers are in {}", launchers.display());
+            }
+            Some("list") => {
+                for x in user_profile::profile_packages(get_current_uid()) {
+                    println!("{}-{}", x.name, x.version);
+                }
+            }
+            Some(x) => {
+                error!("Unknown user command {x}!");
+                print_help();
+                exit(exitcode::USAGE);
+            }
+            None => {
+                error!("Not enough arguments!");
+                print_help();
+                exit(exitcode::USAGE);
+            }
+        },
+        "init" => {
+            command_requires_root_uid();
+            exit(init::run(&init::get_init_config())?);
+        }
+        "cleanup" => {
+            command_requires_root_uid();
+            for x in env::remove_stale_environments()
+                .context("Failed to remove stale environments!")?
+            {
+                println!("Removed {}", x.display());
+            }
+            for x in cgroup::remove_stale()
+                .context("Failed to remove stale cgroups!")?
+            {
+                println!("Removed cgroup {x}");
+            }
+        }
+        "config" => match args.get(2).map(|x| x.as_str()) {
+            Some("show") => {
+                set_effective_uid(get_current_uid())?;
+                let dpt =
+                    read_dpt_file(take_profile_option(&args[3..])?.as_deref())?;
+                println!(
+                    "{}",
+                    ron::ser::to_string_pretty(
+                        &dpt,
+                        ron::ser::PrettyConfig::default()
+                    )?
+                );
+            }
+            Some(x) => {
+                error!("Unknown config command {x}!");
+                print_help();
+                exit(exitcode::USAGE);
+            }
+            None => {
+                error!("Not enough arguments!");
+                print_help();
+                exit(exitcode::USAGE);
+            }
+        },
+        "check" => {
+            set_effective_uid(get_current_uid())?;
+            let profile = take_profile_option(&args[2..])?;
+            let (dpt, files) =
+                match dpt_file::read_dpt_files(profile.as_deref()) {
+                    Ok(x) => x,
+                    Err(x) => {
+                        error!("{x:#}");
+                        exit(exitcode::DATAERR);
+                    }
+                };
+            let mut issues = Vec::<(PathBuf, lint::LintIssue)>::new();
+            for (path, file) in &files {
+                for x in dpt_file::check_dpt_file_names(file) {
+                    issues.push((path.clone(), x));
+                }
+            }
+            issues.extend(dpt_file::check_dpt_file(&dpt));
+            let mut errors = 0;
+            for (path, issue) in &issues {
+                match issue.severity {
+                    lint::Severity::Error => {
+                        errors += 1;
+                        error!("{}: {issue}", path.display());
+                    }
+                    lint::Severity::Warning => {
+                        warn!("{}: {issue}", path.display())
+                    }
+                }
+            }
+            if issues.is_empty() {
+                info!("No problems found");
+            }
+            if errors > 0 {
+                exit(exitcode::DATAERR);
+            }
+        }
+        "conflicts" => {
+            set_effective_uid(get_current_uid())?;
+            let installed = get_installed_packages()?;
+            let pkgs = if argc > 2 {
+                vec![friendly_str_to_package(&args[2], &installed)?]
+            } else {
+                dpt_file_packages(&read_dpt_file(None)?, &installed)?
+            };
+            let mut found = false;
+            for (pkg, conflicts) in
+                env::conflicts_for_packages(&pkgs, &installed)?
+            {
+                for x in &conflicts {
+                    println!("{}-{}: {x}", pkg.name, pkg.version);
+                    found |= !x.intentional;
+                }
+            }
+            if found {
+                exit(exitcode::DATAERR);
+            }
         }
         "run" => {
-            if argc < 3 {
+            if args.get(2).map(|x| x.as_str()) == Some("--project") {
+                exit(run_in_project(&args[3..], false)?);
+            }
+            let (opts, rest) = run::take_run_options(&args[2..], true)?;
+            if rest.is_empty() {
                 error!("Not enough arguments!");
                 exit(exitcode::USAGE);
             }
             let pkg =
-                friendly_str_to_package(&args[2], &get_installed_packages()?)?;
+                friendly_str_to_package(&rest[0], &get_installed_packages()?)?;
             let uid = get_current_uid();
             if uid == 0 && std::env::var("SUDO_USER").is_ok() {
                 warn!("When running `dpt run` using sudo, the inner package gets run as root. Use setuid instead of sudo to run it as yourself");
             }
-            set_current_uid(0)?;
-            let mut run_args = Vec::<String>::new();
-            if argc > 3 {
-                for arg in &args[3..] {
-                    run_args.push(arg.clone());
-                }
+            become_root()?;
+            let run_args = rest[1..].to_vec();
+            exit(run::run_pkg(
+                &pkg, uid, run_args, None, false, false, &opts,
+            )?);
+        }
+        "exec" => {
+            let (opts, rest) = run::take_run_options(&args[2..], true)?;
+            if rest.is_empty() {
+                error!("Not enough arguments!");
+                exit(exitcode::USAGE);
             }
-            exit(run::run_pkg(&pkg, uid, run_args, None, false, false)?);
+            let pkg = bin_index::get_package_for_bin(
+                &rest[0],
+                &get_installed_packages()?,
+            )?;
+            let uid = get_current_uid();
+            become_root()?;
+            exit(run::run_pkg(
+                &pkg.to_package(),
+                uid,
+                rest[1..].to_vec(),
+                Some(&rest[0]),
+                false,
+                false,
+                &opts,
+            )?);
         }
         "run-multi" => {
-            if argc < 3 {
+            let (opts, rest) = run::take_run_options(&args[2..], false)?;
+            if rest.is_empty() {
                 error!("Not enough arguments!");
                 exit(exitcode::USAGE);
             }
@@ -280,7 +445,7 @@ fn main() -> Result<()> {
             let mut packages_to_run = Vec::<Package>::new();
             let mut previous_was_cmd = false;
             let mut cmd: Option<&str> = None;
-            for pkg in &args[2..] {
+            for pkg in &rest {
                 if pkg == "--" {
                     break;
                 }
@@ -303,12 +468,12 @@ fn main() -> Result<()> {
             if uid == 0 && std::env::var("SUDO_USER").is_ok() {
                 warn!("When running `dpt run` using sudo, the inner package gets run as root. Use setuid instead of sudo to run it as yourself");
             }
-            set_current_uid(0)?;
+            become_root()?;
 
             let mut run_args = Vec::<String>::new();
-            if argc > 3 {
+            if rest.len() > 1 {
                 let mut active = false;
-                for arg in &args[3..] {
+                for arg in &rest[1..] {
                     if active {
                         run_args.push(arg.clone());
                     } else {
@@ -325,10 +490,12 @@ fn main() -> Result<()> {
                 cmd,
                 false,
                 false,
+                &opts,
             )?);
         }
         "dev-env" => {
-            if argc < 3 {
+            let (opts, rest) = run::take_run_options(&args[2..], false)?;
+            if rest.is_empty() {
                 error!("Not enough arguments!");
                 exit(exitcode::USAGE);
             }
@@ -337,13 +504,23 @@ fn main() -> Result<()> {
             if uid == 0 && std::env::var("SUDO_USER").is_ok() {
                 warn!("When running `dpt dev-env` using sudo, the inner package gets run as root. Use setuid instead of sudo to run it as yourself");
             }
-            set_current_uid(0)?;
+            become_root()?;
 
-            let packages = get_all_available_packages()?;
+            let mut names = Vec::<String>::new();
+            for arg in &rest {
+                if arg == "--" {
+                    break;
+                }
+                names.push(arg.clone());
+                if let Ok(x) = string_to_package(arg) {
+                    names.push(x.name);
+                }
+            }
+            let packages = get_available_packages_for(&names)?;
             let mut packages_to_run = Vec::<Package>::new();
             let mut previous_was_cmd = false;
             let mut cmd: Option<&str> = None;
-            for pkg in &args[2..] {
+            for pkg in &rest {
                 if pkg == "--" {
                     break;
                 }
@@ -380,9 +557,9 @@ fn main() -> Result<()> {
             )?;
 
             let mut run_args = Vec::<String>::new();
-            if argc > 3 {
+            if rest.len() > 1 {
                 let mut active = false;
-                for arg in &args[3..] {
+                for arg in &rest[1..] {
                     if active {
                         run_args.push(arg.clone());
                     } else {
@@ -399,81 +576,185 @@ fn main() -> Result<()> {
                 cmd,
                 true,
                 false,
+                &opts,
             )?);
         }
+        "env" => {
+            if argc < 4 && args.get(2).map(|x| x.as_str()) != Some("list") {
+                error!("Not enough arguments!");
+                print_help();
+                exit(exitcode::USAGE);
+            }
+            match args[2].as_str() {
+                "create" => {
+                    command_requires_root_uid();
+                    let lock =
+                        named_env::create_named_env(&args[3], &args[4..])?;
+                    for x in &lock.packages {
+                        println!("{}-{}", x.name, x.version);
+                    }
+                }
+                "enter" => {
+                    let (opts, rest) = run::take_run_options(&args[3..], true)?;
+                    if rest.is_empty() {
+                        error!("Not enough arguments!");
+                        exit(exitcode::USAGE);
+                    }
+                    let lock = named_env::read_named_env_lock(&rest[0])?;
+                    let mut cmd = &rest[1..];
+                    if cmd.first().map(|x| x.as_str()) == Some("--") {
+                        cmd = &cmd[1..];
+                    }
+                    let uid = get_current_uid();
+                    become_root()?;
+                    exit(run::run_locked_packages(
+                        &lock,
+                        uid,
+                        cmd.get(1..).unwrap_or_default().to_vec(),
+                        cmd.first().map(|x| x.as_str()).unwrap_or("sh"),
+                        &opts,
+                    )?);
+                }
+                "update" => {
+                    command_requires_root_uid();
+                    let (old, new) = named_env::update_named_env(&args[3])?;
+                    if old.packages == new.packages {
+                        println!("Already up to date");
+                    }
+                    for (old, new) in old.packages.iter().zip(&new.packages) {
+                        if old != new {
+                            println!(
+                                "{}: {} -> {}",
+                                new.name, old.version, new.version
+                            );
+                        }
+                    }
+                }
+                "delete" => {
+                    command_requires_root_uid();
+                    named_env::delete_named_env(&args[3])?;
+                }
+                "list" => {
+                    for name in named_env::list_named_envs()? {
+                        let lock = named_env::read_named_env_lock(&name)?;
+                        println!(
+                            "{name}: {}",
+                            bin_index::package_names(&lock.packages)
+                        );
+                    }
+                }
+                x => {
+                    error!("Unknown env command {x}!");
+                    print_help();
+                    exit(exitcode::USAGE);
+                }
+            }
+        }
+        "shell" => {
+            exit(run_in_project(&args[2..], true)?);
+        }
         "gen-index" => {
             set_effective_uid(get_current_uid())?;
-            let mut out = RepositoryIndex { packages: vec![] };
-
-            let dpts = walkdir::WalkDir::new(".")
-                .follow_links(true)
-                .into_iter()
-                .filter(|x| {
-                    x.is_ok()
-                        && x.as_ref().unwrap().path().extension().is_some()
-                        && x.as_ref()
-                            .unwrap()
-                            .path()
-                            .extension()
-                            .unwrap()
-                            .to_str()
-                            == "dpt".into()
-                })
-                .map(|x| x.unwrap().path().to_owned())
-                .collect::<Vec<PathBuf>>();
-            for ent in dpts.into_iter().progress().with_style(
-                indicatif::ProgressStyle::default_bar()
-                    .template(PROGRESS_STYLE)?
-                    .progress_chars(PROGRESS_CHARS),
-            ) {
-                let mut pkg = decompress_pkg_read(std::fs::File::open(&ent)?)?;
-                for pkg_ent in pkg.entries()? {
-                    let mut pkg_ent = pkg_ent?;
-                    if pkg_ent.path()? == Path::new("dpt/pkg.ron")
-                        || pkg_ent.path()? == Path::new("./dpt/pkg.ron")
-                    {
-                        let mut buf = String::new();
-                        pkg_ent.read_to_string(&mut buf)?;
-                        let cfg = get_package_config(&buf)?;
-
-                        let ent_path = match ent.strip_prefix("./") {
-                            Ok(x) => x,
-                            Err(_) => &ent,
-                        };
-                        let ent_path = ent_path
-                            .to_str()
-                            .ok_or(anyhow!(
-                                "Failed to convert file path into a str"
-                            ))?
-                            .to_string();
-
-                        out.packages.push(OnlinePackage {
-                            name: cfg.name,
-                            version: cfg.version,
-                            url: ent_path,
-                            depends: cfg.depends,
-                        });
-                        break;
+            let mut shard_prefix_length: Option<usize> = None;
+            let mut i = 2;
+            while i < argc {
+                match args[i].as_str() {
+                    "--shards" => {
+                        i += 1;
+                        let len: usize = args
+                            .get(i)
+                            .context("--shards needs a prefix length")?
+                            .parse()
+                            .context("Invalid prefix length for --shards")?;
+                        if len == 0 {
+                            bail!("The shard prefix length must be at least 1");
+                        }
+                        shard_prefix_length = Some(len);
+                    }
+                    x => {
+                        error!("Unknown argument {x}!");
+                        exit(exitcode::USAGE);
                     }
                 }
+                i += 1;
             }
+            index::generate_index(Path::new("."), shard_prefix_length)?;
+        }
+        "scan-deps" => {
+            if argc < 3 {
+                error!("Not enough arguments!");
+                exit(exitcode::USAGE);
+            }
+            set_effective_uid(get_current_uid())?;
+            let dir = Path::new(&args[2]);
+            let write_deps = args[3..].contains(&"--write".to_string());
+            let cfg_path = dir.join("dpt/pkg.ron");
+            let text = std::fs::read_to_string(&cfg_path)
+                .context(anyhow!("Failed to read {}", cfg_path.display()))?;
+            let cfg = get_package_config(&text)?;
 
-            std::fs::write(
-                "index.ron",
-                ron::ser::to_string_pretty(
-                    &out,
-                    ron::ser::PrettyConfig::default(),
-                )?,
-            )?;
+            let elfs = scan::scan_directory(dir)?;
+            let (suggested, unresolved) = scan::suggest_dependencies(
+                &cfg,
+                &scan::required_libraries(&elfs),
+                &scan::library_names(dir),
+                &get_soname_index()?.sonames,
+            );
+            for lib in &unresolved {
+                warn!("No package in the repositories provides {lib}!");
+            }
+            for dep in &suggested {
+                info!("Missing dependency: {}", dep.name);
+            }
+            if write_deps && !suggested.is_empty() {
+                std::fs::write(
+                    &cfg_path,
+                    scan::add_dependencies(&text, &suggested).context(
+                        anyhow!("Failed to update {}", cfg_path.display()),
+                    )?,
+                )?;
+                info!("Updated {}", cfg_path.display());
+            }
+        }
+        "lint" => {
+            if argc < 3 {
+                error!("Not enough arguments!");
+                exit(exitcode::USAGE);
+            }
+            set_effective_uid(get_current_uid())?;
+            let installed =
+                get_installed_packages_without_dpt_file().unwrap_or_default();
+            let mut errors = 0;
+            for path in &args[2..] {
+                let issues = lint::lint_path(Path::new(path), &installed)
+                    .context(anyhow!("Failed to lint {path}"))?;
+                for issue in &issues {
+                    match issue.severity {
+                        lint::Severity::Error => {
+                            errors += 1;
+                            error!("{path}: {issue}");
+                        }
+                        lint::Severity::Warning => warn!("{path}: {issue}"),
+                    }
+                }
+                if issues.is_empty() {
+                    info!("{path}: No problems found");
+                }
+            }
+            if errors > 0 {
+                exit(exitcode::DATAERR);
+            }
         }
         "run-pkg-second-stage-not-intended-for-interactive-use" => {
             command_requires_root_uid();
-            if argc < 6 {
+            if argc < 7 {
                 error!("Not enough arguments!");
                 exit(exitcode::USAGE);
             }
             let uid: u32 = args[3].parse()?;
             let replace_current_process = args[5] == "replace";
+            let stage: run::StageOptions = ron::from_str(&args[6])?;
+            let lowerdir = &stage.lowerdir;
             let prev_dir =
                 std::env::current_dir().unwrap_or(PathBuf::from_str("/")?);
             let out_dir = Path::new(&args[2]);
@@ -482,8 +763,39 @@ fn main() -> Result<()> {
                 .recursive(true)
                 .create(&out_dir)?;
 
+            // This is created before anything is unshared, since the cgroup
+            // hierarchy is needed
+            let cgroup = if stage.limits.is_empty() {
+                None
+            } else {
+                Some(Arc::new(cgroup::Cgroup::create(&stage.limits)?))
+            };
+
+            let creds = creds::Credentials::of_invoking_user(uid)?;
+            let user = read_dpt_lock_file()
+                .ok()
+                .and_then(|x| creds::lookup_user(uid, &x).cloned());
+
             let dpt_dir = get_dpt_dir();
-            unshare(CloneFlags::CLONE_NEWNS)?;
+            if is_rootless() {
+                run::enter_user_namespace()?;
+            } else {
+                unshare(CloneFlags::CLONE_NEWNS)?;
+            }
+            // Signals are passed on to the program instead
+            signals::block_signals()?;
+            let sandbox = stage.sandbox;
+            if !sandbox.clone_flags().is_empty() {
+                unshare(sandbox.clone_flags())?;
+            }
+            if sandbox.net {
+                sandbox::loopback_up()?;
+            }
+            if sandbox.pid {
+                if let Some(code) = sandbox::fork_into_pid_namespace()? {
+                    exit(code);
+                }
+            }
             nix::mount::mount(
                 Option::<&Path>::None,
                 "/",
@@ -491,28 +803,48 @@ fn main() -> Result<()> {
                 MsFlags::MS_REC | MsFlags::MS_SLAVE,
                 Option::<&Path>::None,
             )?;
+            let out_dir = &if lowerdir.is_empty() {
+                out_dir.to_path_buf()
+            } else {
+                run::mount_overlay(out_dir, lowerdir)?
+            };
             run::bind_mount(out_dir, out_dir, true)?;
 
-            // Bind mount dpt dir inside the out_dir
+            // Mounts copied into a user namespace are locked, and the
+            // namespace goes away with us anyway
+            let mut mounts = run::MountGuard::new(is_rootless());
+            // Only the tmpfs of an overlay is ours to create mountpoints in
+            run::apply_bind_plan(
+                out_dir,
+                &stage.binds,
+                &creds,
+                !lowerdir.is_empty(),
+                &mut mounts,
+            )?;
+            if sandbox.pid {
+                // The proc of the host is still visible here, which rootless
+                // mode needs to be allowed to mount a new one
+                sandbox::mount_proc(&run::join_proper(
+                    out_dir,
+                    Path::new("/proc"),
+                )?)?;
+            }
+
+            // Bind mount dpt dir inside the out_dir. This comes after the
+            // binds above since the dpt dir may be inside of one of them, like
+            // it is in rootless mode.
             let dpt_target = run::join_proper(&out_dir, &dpt_dir)?;
             run::bind_mount(&dpt_dir, &dpt_target, false)?;
-
-            let mut binds = Vec::<PathBuf>::new();
-
-            for bind in vec![
-                "dev", "mnt", "media", "run", "var", "home", "tmp", "proc",
-                "sys",
-            ] {
-                let dir = Path::new("/").join(bind);
-                let dir_target = out_dir.join(bind);
-                if dir_target.exists() {
-                    continue;
-                }
-                if !dir.exists() {
-                    continue;
+            mounts.push(dpt_target.clone());
+            // Don't let anything in the environment change package files. In
+            // rootless mode the user owns them anyway.
+            if !is_rootless() {
+                for dir in run::READ_ONLY_DIRS {
+                    let dir = dpt_target.join(dir);
+                    if dir.is_dir() {
+                        run::bind_mount_read_only(&dir)?;
+                    }
                 }
-                run::bind_mount(&dir, &dir_target, true)?;
-                binds.push(dir_target);
             }
 
             std::env::set_current_dir(out_dir)?;
@@ -527,49 +859,74 @@ fn main() -> Result<()> {
                 std::env::set_current_dir("/")?;
             }
             unmount("/.old_root", UnmountFlags::DETACH)?;
+            // The environment is shared with other runs, so keep it intact.
+            // Overlays already keep writes in their own tmpfs.
+            if lowerdir.is_empty() {
+                nix::mount::mount(
+                    Option::<&Path>::None,
+                    "/",
+                    Option::<&Path>::None,
+                    MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                    Option::<&Path>::None,
+                )?;
+            }
             let mut p = std::process::Command::new(&args[4]);
+            if let Some(user) = &user {
+                p.env("HOME", &user.home)
+                    .env("USER", &user.username)
+                    .env("LOGNAME", &user.username);
+            }
+            // User namespaces don't allow setgroups
+            let set_groups = !is_rootless();
+            // seccomp filters need no_new_privs once root is given up
+            let no_new_privs = stage.no_new_privs
+                || sandbox.seccomp
+                || creds::no_new_privs_enabled();
+            let filter = if sandbox.seccomp {
+                Some(sandbox::SeccompFilter::default_filter()?)
+            } else {
+                None
+            };
+            let cgroup_clone = cgroup.clone();
             unsafe {
                 p.pre_exec(move || {
-                    set_current_uid(uid.clone())?;
-                    set_effective_uid(get_current_uid())?;
+                    signals::unblock_signals()?;
+                    if let Some(cgroup) = &cgroup_clone {
+                        cgroup.enter()?;
+                    }
+                    creds.apply(set_groups)?;
+                    if no_new_privs {
+                        nix::sys::prctl::set_no_new_privs()?;
+                    }
+                    if let Some(filter) = &filter {
+                        filter.apply()?;
+                    }
                     Ok(())
                 })
             };
-            if argc > 6 {
-                for a in &args[6..] {
+            if argc > 7 {
+                for a in &args[7..] {
                     p.arg(a);
                 }
             }
-            exit(if replace_current_process {
+            // The init of a PID namespace has to stay around, and so does
+            // whoever removes the cgroup
+            if replace_current_process && !sandbox.pid && cgroup.is_none() {
                 let err = p.exec();
                 bail!("Failed to run process! Error: {err}");
-            } else {
-                let p: Arc<Mutex<std::process::Child>> =
-                    Arc::new(Mutex::new(p.spawn()?));
-
-                let proc_arc_clone = Arc::clone(&p);
-                ctrlc::set_handler(move || {
-                    let l = proc_arc_clone.lock();
-                    if let Ok(mut x) = l {
-                        let _ = x.kill();
-                    };
-                })
-                .context("Failed to register ctrlc singal handler")?;
-                let l = p.lock();
-                let exit_code = if let Ok(mut x) = l {
-                    x.wait()?.code().unwrap_or(89)
-                } else {
-                    243
-                };
-
-                binds.push(dpt_target);
-
-                for bind in &binds {
-                    run::unmount_recursive(bind)?;
-                }
-
-                exit_code
-            })
+            }
+            let child = Pid::from_raw(p.spawn()?.id() as i32);
+            drop(p);
+            // The outer process already forwards to the whole group when
+            // there is an init
+            let exit_code = signals::wait_in_group(child, !sandbox.pid)?;
+            if let Some(cgroup) = &cgroup {
+                cgroup.report();
+            }
+            // exit() skips destructors
+            drop(cgroup);
+            drop(mounts);
+            exit(exit_code);
         }
         cmd => {
             error!("Unknown command {}!", cmd);
@@ -587,6 +944,17 @@ fn remove_duplicates<T: Eq + std::hash::Hash + Clone>(mut l: Vec<T>) -> Vec<T> {
     l
 }
 
+/// Gets the installed versions of the packages listed in a dpt file
+fn dpt_file_packages(
+    dpt: &DptFile,
+    pkgs: &Vec<OnlinePackage>,
+) -> Result<Vec<Package>> {
+    dpt.packages
+        .iter()
+        .map(|x| Ok(newest_package_from_name(&x.name, pkgs)?.to_package()))
+        .collect()
+}
+
 fn friendly_str_to_package(
     arg: &str,
     pkgs: &Vec<OnlinePackage>,
@@ -604,8 +972,90 @@ fn friendly_str_to_package(
     Ok(pkg)
 }
 
+/// Runs a command in the environment of the project in the working directory.
+/// The packages are resolved and locked the first time, and the lock file is
+/// used after that. Without a command `shell` runs `sh`.
+fn run_in_project(args: &[String], shell: bool) -> Result<i32> {
+    let uid = get_current_uid();
+    let gid = uzers::get_current_gid();
+    let file = project_env::find_project_file(&std::env::current_dir()?)
+        .context(anyhow!(
+            "No {} found in this directory or its parents!",
+            project_env::PROJECT_FILE
+        ))?;
+    // The project belongs to the user, so it is read and written as them
+    let (env, lock) = {
+        let _user = switch_user_group(uid, gid)?;
+        let env = project_env::read_project_env(&file)?;
+        let lock = project_env::read_project_lock(&file, &env);
+        (env, lock)
+    };
+
+    let mut all_args = project_env::feature_args(&env)?;
+    all_args.extend_from_slice(args);
+    let (mut opts, rest) = run::take_run_options(&all_args, true)?;
+    project_env::apply_vars(&env, &mut opts)?;
+    let mut rest = &rest[..];
+    if rest.first().map(|x| x.as_str()) == Some("--") {
+        rest = &rest[1..];
+    }
+    let cmd = match rest.first() {
+        Some(x) => x.as_str(),
+        None if shell => "sh",
+        None => {
+            error!("Not enough arguments!");
+            exit(exitcode::USAGE);
+        }
+    };
+
+    become_root()?;
+    let lock = match lock {
+        Some(x) => {
+            project_env::install_project_lock(&x)?;
+            x
+        }
+        None => {
+            info!("Resolving the packages of {}", file.display());
+            let lock = project_env::lock_project_env(&env)?;
+            let _user = switch_user_group(uid, gid)?;
+            project_env::write_project_lock(&file, &lock)?;
+            lock
+        }
+    };
+    run::run_locked_packages(
+        &lock,
+        uid,
+        rest.get(1..).unwrap_or_default().to_vec(),
+        cmd,
+        &opts,
+    )
+}
+
+/// Takes `--profile NAME`, the only option of commands that read `dpt.ron`
+fn take_profile_option(args: &[String]) -> Result<Option<String>> {
+    match args {
+        [] => Ok(None),
+        [x, name] if x == "--profile" => Ok(Some(name.clone())),
+        [x] if x == "--profile" => bail!("--profile needs a name"),
+        [x, ..] => {
+            error!("Unknown argument {x}!");
+            exit(exitcode::USAGE);
+        }
+    }
+}
+
+/// Switches the real uid to root, unless running rootless
+fn become_root() -> Result<()> {
+    if !is_rootless() {
+        set_current_uid(0)?;
+    }
+    Ok(())
+}
+
+/// Exits unless the user is root. Rootless dpt only changes the user's own dpt
+/// directory, so it is always allowed.
 fn command_requires_root_uid() {
-    if uzers::get_current_uid() != 0 {
+    if !is_rootless() && uzers::get_current_uid() != 0 {
         error!("You need to be root to run this!");
         exit(exitcode::USAGE);
     }
@@ -620,7 +1070,18 @@ Dpt, package management, done right.
 Commands:
     rebuild         Rebuilds the environment according to the dpt file.
     run             Runs a program
+    exec            Runs a binary from whichever package provides it
     run-multi       Runs the first program specified in an env with the rest
-    gen-index       Generates the index file for a package repository at PWD"
+    env             Creates, enters, updates, deletes or lists named environments
+    shell           Enters the environment of the project in the working directory
+    user            Rebuilds or lists the packages of your own profile
+    gen-index       Generates the index file for a package repository at PWD
+    lint            Checks package directories or .dpt files for problems
+    conflicts       Lists files that more than one package provides
+    config          Shows dpt.ron with its includes and host section merged in
+    check           Checks dpt.ron and the files it includes for mistakes
+    init            Runs the built-in init, as PID 1
+    cleanup         Removes what crashed or killed runs left behind
+    scan-deps       Finds missing library dependencies of a package directory"
     );
 }
@@ -0,0 +1,301 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    env::environment_key_for_packages,
+    index::write_atomically,
+    pkg::{string_to_package, Package},
+    repo::{
+        get_available_packages_for, install_pkgs_and_dependencies,
+        newest_package_from_name, package_to_onlinepackage, OnlinePackage,
+    },
+    run::{get_random_string, DirGuard},
+    store::{get_dpt_dir, get_installed_packages_without_dpt_file},
+};
+
+/// What a named environment is made of, as it was asked for
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamedEnv {
+    /// Like `gcc`, `gcc-14.2.0` or `python@3.12`
+    pub packages: Vec<String>,
+}
+
+/// The packages that a named environment was resolved to
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamedEnvLock {
+    /// One for each of the requested packages, in order
+    pub packages: Vec<Package>,
+    /// Everything that was installed for them, including dependencies
+    pub resolved: Vec<Package>,
+}
+
+pub fn get_named_envs_location() -> PathBuf {
+    get_dpt_dir().join("envs")
+}
+
+fn named_env_dir(name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        bail!("Invalid environment name '{name}'!");
+    }
+    Ok(get_named_envs_location().join(name))
+}
+
+/// Lists the names of every named environment
+pub fn list_named_envs() -> Result<Vec<String>> {
+    let Ok(entries) = std::fs::read_dir(get_named_envs_location()) else {
+        return Ok(Vec::new());
+    };
+    let mut ret = Vec::new();
+    for ent in entries {
+        let path = ent?.path();
+        let name = path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !name.starts_with('.') && path.join("env.lock").is_file() {
+            ret.push(name);
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+pub fn read_named_env(name: &str) -> Result<NamedEnv> {
+    let path = named_env_dir(name)?.join("env.ron");
+    Ok(ron::from_str(&std::fs::read_to_string(&path).context(
+        anyhow!("Environment '{name}' doesn't exist!"),
+    )?)?)
+}
+
+pub fn read_named_env_lock(name: &str) -> Result<NamedEnvLock> {
+    let path = named_env_dir(name)?.join("env.lock");
+    Ok(ron::from_str(&std::fs::read_to_string(&path).context(
+        anyhow!("Environment '{name}' doesn't exist!"),
+    )?)?)
+}
+
+/// The name of the package that a request is for
+pub fn request_name(request: &str) -> String {
+    match request.split_once('@') {
+        Some((name, _)) => name.to_string(),
+        None => match string_to_package(request) {
+            Ok(x) => x.name,
+            Err(_) => request.to_string(),
+        },
+    }
+}
+
+/// Whether `version` is `prefix` or starts with its components
+fn version_matches(version: &str, prefix: &str) -> bool {
+    version == prefix || version.starts_with(&format!("{prefix}."))
+}
+
+/// Whether `pkg` is something that `request` could have been resolved to
+pub fn request_matches(request: &str, pkg: &Package) -> bool {
+    if let Some((name, prefix)) = request.split_once('@') {
+        return pkg.name == name && version_matches(&pkg.version, prefix);
+    }
+    if let Ok(x) = string_to_package(request) {
+        if x.name == pkg.name && x.version == pkg.version {
+            return true;
+        }
+    }
+    pkg.name == request
+}
+
+/// Finds the package that a request means. `name@version` picks the newest
+/// version that starts with the given components, so `python@3.12` can be
+/// `3.12.4` but not `3.120`.
+pub fn package_for_request(
+    request: &str,
+    pkgs: &Vec<OnlinePackage>,
+) -> Result<OnlinePackage> {
+    if let Some((name, prefix)) = request.split_once('@') {
+        let matching = pkgs
+            .iter()
+            .filter(|x| x.name == name && version_matches(&x.version, prefix))
+            .cloned()
+            .collect::<Vec<OnlinePackage>>();
+        return newest_package_from_name(name, &matching)
+            .context(anyhow!("No version of {name} matches {prefix}"));
+    }
+    if let Ok(x) = string_to_package(request) {
+        if let Ok(x) = package_to_onlinepackage(&x, pkgs) {
+            return Ok(x);
+        }
+    }
+    newest_package_from_name(request, pkgs)
+}
+
+/// Resolves the packages of `env` with the repositories and installs them
+pub fn lock_named_env(env: &NamedEnv) -> Result<NamedEnvLock> {
+    let mut names = Vec::<String>::new();
+    for x in &env.packages {
+        names.push(x.clone());
+        names.push(request_name(x));
+    }
+    let available = get_available_packages_for(&names)?;
+    let selected = env
+        .packages
+        .iter()
+        .map(|x| {
+            package_for_request(x, &available)
+                .context(anyhow!("Package `{x}` not found!"))
+        })
+        .collect::<Result<Vec<OnlinePackage>>>()?;
+    let resolved = install_pkgs_and_dependencies(&selected, &available, false)?;
+    Ok(NamedEnvLock {
+        packages: selected.into_iter().map(|x| x.to_package()).collect(),
+        resolved: resolved.into_iter().map(|x| x.to_package()).collect(),
+    })
+}
+
+/// The installed packages that `lock` resolved to. Environments of the lock
+/// are resolved with only these, so they get exactly the locked versions.
+pub fn locked_packages(
+    lock: &NamedEnvLock,
+    installed: &Vec<OnlinePackage>,
+) -> Result<Vec<OnlinePackage>> {
+    lock.resolved
+        .iter()
+        .map(|x| {
+            package_to_onlinepackage(x, installed).context(anyhow!(
+                "Package {}-{} of the lock file isn't installed!",
+                x.name,
+                x.version
+            ))
+        })
+        .collect()
+}
+
+pub fn write_ron<T: Serialize>(
+    path: &std::path::Path,
+    value: &T,
+) -> Result<()> {
+    write_atomically(
+        path,
+        ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())?
+            .as_bytes(),
+    )
+}
+
+/// Creates the named environment `name` out of `requests`, installing the
+/// packages that it needs
+pub fn create_named_env(
+    name: &str,
+    requests: &[String],
+) -> Result<NamedEnvLock> {
+    let dir = named_env_dir(name)?;
+    if dir.exists() {
+        bail!("Environment '{name}' already exists!");
+    }
+    if requests.is_empty() {
+        bail!("No packages specified!");
+    }
+    let envs = get_named_envs_location();
+    std::fs::DirBuilder::new().recursive(true).create(&envs)?;
+
+    // Only moved into place once it is complete
+    let tmp = DirGuard::new(envs.join(format!(
+        ".{name}.tmp-{}-{}",
+        std::process::id(),
+        get_random_string(6)
+    )));
+    std::fs::DirBuilder::new().create(tmp.path())?;
+    let env = NamedEnv {
+        packages: requests.to_vec(),
+    };
+    let lock = lock_named_env(&env)?;
+    write_ron(&tmp.path().join("env.ron"), &env)?;
+    write_ron(&tmp.path().join("env.lock"), &lock)?;
+    std::fs::rename(tmp.path(), &dir)
+        .context(anyhow!("Failed to create environment '{name}'"))?;
+    Ok(lock)
+}
+
+/// Resolves the packages of a named environment again, installing newer
+/// versions. Returns the old and the new lock.
+pub fn update_named_env(name: &str) -> Result<(NamedEnvLock, NamedEnvLock)> {
+    let env = read_named_env(name)?;
+    let old = read_named_env_lock(name)?;
+    let new = lock_named_env(&env)?;
+    write_ron(&named_env_dir(name)?.join("env.lock"), &new)?;
+    Ok((old, new))
+}
+
+pub fn delete_named_env(name: &str) -> Result<()> {
+    let dir = named_env_dir(name)?;
+    if !dir.is_dir() {
+        bail!("Environment '{name}' doesn't exist!");
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// The keys of the cached environments of every named environment. These are
+/// roots that pruning the cache keeps.
+pub fn cached_environment_keys() -> Vec<String> {
+    let Ok(installed) = get_installed_packages_without_dpt_file() else {
+        return Vec::new();
+    };
+    let mut ret = Vec::new();
+    for name in list_named_envs().unwrap_or_default() {
+        match read_named_env_lock(&name).and_then(|x| {
+            environment_key_for_packages(
+                &x.packages,
+                &locked_packages(&x, &installed)?,
+                true,
+            )
+        }) {
+            Ok(x) => ret.push(x),
+            Err(x) => {
+                warn!("Can't keep the cache of environment '{name}': {x}")
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(name: &str, version: &str) -> OnlinePackage {
+        OnlinePackage {
+            name: name.into(),
+            version: version.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn package_for_request_1() {
+        let pkgs = vec![
+            pkg("python", "3.11.9"),
+            pkg("python", "3.12.1"),
+            pkg("python", "3.12.4"),
+            pkg("python", "3.120.0"),
+            pkg("gcc", "14.2.0"),
+        ];
+        let version = |x: &str| package_for_request(x, &pkgs).unwrap().version;
+        assert_eq!(version("python@3.12"), "3.12.4");
+        assert_eq!(version("python@3.11.9"), "3.11.9");
+        assert_eq!(version("python"), "3.120.0");
+        assert_eq!(version("python-3.12.1"), "3.12.1");
+        assert_eq!(version("gcc"), "14.2.0");
+        assert!(package_for_request("python@3.13", &pkgs).is_err());
+        assert_eq!(request_name("python@3.12"), "python");
+        assert_eq!(request_name("python-3.12.1"), "python");
+
+        let locked = Package::new("python".into(), "3.12.4".into());
+        assert!(request_matches("python", &locked));
+        assert!(request_matches("python@3.12", &locked));
+        assert!(request_matches("python-3.12.4", &locked));
+        assert!(!request_matches("python@3.11", &locked));
+        assert!(!request_matches("python-3.12.1", &locked));
+        assert!(!request_matches("gcc", &locked));
+    }
+}
@@ -3,11 +3,14 @@ use ron;
 use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
+    collections::BTreeMap,
     fmt::{self, Display},
     io::BufRead,
 };
 use tar::Archive;
 
+use crate::{cgroup::Limits, store::is_rootless};
+
 #[derive(Debug, Clone, Hash, Eq, Serialize, Deserialize)]
 pub struct Dependency {
     pub name: String,
@@ -44,12 +47,47 @@ pub enum Glue {
     Glob(Vec<String>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct PackageConfig {
     pub name: String,
     pub version: String,
     pub depends: Vec<Dependency>,
     pub glue: Vec<Glue>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// SPDX license expression, e.g. `MIT OR Apache-2.0`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maintainers: Vec<String>,
+    /// Size in bytes of the unpacked package
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed_size: Option<u64>,
+    /// Size in bytes of the compressed `.dpt`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_size: Option<u64>,
+    /// Globs of files that this package intentionally provides in place of
+    /// other packages' files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<String>,
+    /// Variables to set for programs in environments with this package
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Host paths to bind into environments with this package, as
+    /// `SRC[:DST[:ro]]`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub binds: Vec<String>,
+    /// Host paths that must not be visible in environments with this package
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_binds: Vec<String>,
+    /// Run the package with the `--sandbox` profile by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sandbox: Option<bool>,
+    /// Default resource limits when the package is run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<Limits>,
 }
 
 impl PartialEq for PackageConfig {
@@ -165,7 +203,8 @@ pub fn index_or_err_str(s: &Vec<&str>, i: usize) -> Result<String> {
 
 /// Parses the package configuration and bails if not valid.
 pub fn get_package_config(file: &str) -> Result<PackageConfig> {
-    Ok(ron::from_str(file)?)
+    let pkg_file = String::from("#![enable(implicit_some)]\n");
+    Ok(ron::from_str(&(pkg_file + file))?)
 }
 
 /// Parses the name and version from a string.
@@ -192,9 +231,10 @@ pub fn decompress_pkg_read<'a>(
     let zstrm = zstd::Decoder::new(pkg)?;
 
     let mut archive = tar::Archive::new(zstrm);
-    archive.set_unpack_xattrs(true);
+    // Users can't give files away or set most xattrs
+    archive.set_unpack_xattrs(!is_rootless());
     archive.set_preserve_permissions(true);
-    archive.set_preserve_ownerships(true);
+    archive.set_preserve_ownerships(!is_rootless());
     archive.set_overwrite(true);
 
     Ok(archive)
@@ -253,9 +293,39 @@ mod tests {
                     "/usr/lib/systemd/system/*.socket".to_string(),
                 ]),
             ],
+            ..Default::default()
         };
         let x = get_package_config(s).unwrap();
         assert_eq!(x, expected);
+        assert_eq!(x.description, None);
+        assert!(x.maintainers.is_empty());
+    }
+
+    #[test]
+    fn get_pkg_config_metadata() {
+        let s = r###"
+(
+    name: "abcd",
+    version: "1.0",
+    depends: [],
+    glue: [],
+    description: "An example package",
+    license: "MIT OR Apache-2.0",
+    homepage: "https://example.org",
+    maintainers: ["Someone <someone@example.org>"],
+    installed_size: 4096
+)
+"###;
+        let x = get_package_config(s).unwrap();
+        assert_eq!(x.description, Some("An example package".to_string()));
+        assert_eq!(x.license, Some("MIT OR Apache-2.0".to_string()));
+        assert_eq!(x.homepage, Some("https://example.org".to_string()));
+        assert_eq!(
+            x.maintainers,
+            vec!["Someone <someone@example.org>".to_string()]
+        );
+        assert_eq!(x.installed_size, Some(4096));
+        assert_eq!(x.download_size, None);
     }
 
     #[test]
@@ -0,0 +1,219 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    envvars::is_unsafe_var,
+    named_env::{
+        lock_named_env, request_matches, write_ron, NamedEnv, NamedEnvLock,
+    },
+    pkg::Package,
+    repo::{get_available_packages_for, install_pkg, package_to_onlinepackage},
+    run::{take_run_options, RunOptions},
+    store::get_installed_packages_without_dpt_file,
+};
+
+pub const PROJECT_FILE: &str = "dpt-env.ron";
+pub const PROJECT_LOCK_FILE: &str = "dpt-env.lock";
+
+/// The options of `dpt run` that a project can use as features, and whether
+/// they take a value. Projects come from anyone, so they can only restrict the
+/// environment, not bind paths into it or pass variables through.
+const FEATURES: [(&str, bool); 10] = [
+    ("unshare-pid", false),
+    ("unshare-net", false),
+    ("unshare-ipc", false),
+    ("unshare-uts", false),
+    ("seccomp", false),
+    ("hide-home", false),
+    ("memory", true),
+    ("cpus", true),
+    ("pids-max", true),
+    ("io-weight", true),
+];
+
+/// The environment of a project, checked in next to its code
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProjectEnv {
+    /// Like `gcc`, `gcc-14.2.0` or `python@3.12`
+    pub packages: Vec<String>,
+    /// Run options without the leading `--`, like `unshare-net` or
+    /// `memory=1G`
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Variables to set in the environment
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+}
+
+/// Finds the project file in `dir` or the closest of its parents
+pub fn find_project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|x| x.join(PROJECT_FILE))
+        .find(|x| x.is_file())
+}
+
+pub fn read_project_env(path: &Path) -> Result<ProjectEnv> {
+    ron::from_str(
+        &std::fs::read_to_string(path)
+            .context(anyhow!("Failed to read {}", path.display()))?,
+    )
+    .context(anyhow!("Failed to parse {}", path.display()))
+}
+
+/// Turns the features of `env` into run options, which come before the ones
+/// given on the command line
+pub fn feature_args(env: &ProjectEnv) -> Result<Vec<String>> {
+    let mut ret = Vec::<String>::new();
+    for feature in &env.features {
+        let (name, value) = match feature.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (feature.as_str(), None),
+        };
+        match FEATURES.iter().find(|x| x.0 == name) {
+            None => bail!("Unknown feature {name}!"),
+            Some((_, true)) if value.is_none() => {
+                bail!("Feature {name} needs a value, like {name}=VALUE")
+            }
+            Some((_, false)) if value.is_some() => {
+                bail!("Feature {name} doesn't take a value")
+            }
+            _ => {}
+        }
+        ret.push(format!("--{name}"));
+        ret.extend(value.map(str::to_string));
+    }
+    // Checks the values
+    take_run_options(&ret, false)?;
+    Ok(ret)
+}
+
+/// Adds the variables of `env` to `opts`
+pub fn apply_vars(env: &ProjectEnv, opts: &mut RunOptions) -> Result<()> {
+    for (name, value) in &env.vars {
+        if is_unsafe_var(name) {
+            bail!("Variable {name} can't be set by a project!");
+        }
+        opts.vars.insert(name.clone(), value.clone());
+    }
+    Ok(())
+}
+
+/// Reads the lock file next to `path`, if it is still for the packages of
+/// `env`
+pub fn read_project_lock(
+    path: &Path,
+    env: &ProjectEnv,
+) -> Option<NamedEnvLock> {
+    let lock: NamedEnvLock = ron::from_str(
+        &std::fs::read_to_string(path.with_file_name(PROJECT_LOCK_FILE))
+            .ok()?,
+    )
+    .ok()?;
+    if lock.packages.len() != env.packages.len()
+        || !env
+            .packages
+            .iter()
+            .zip(&lock.packages)
+            .all(|(request, pkg)| request_matches(request, pkg))
+    {
+        return None;
+    }
+    Some(lock)
+}
+
+/// Resolves the packages of `env` with the repositories and installs them
+pub fn lock_project_env(env: &ProjectEnv) -> Result<NamedEnvLock> {
+    lock_named_env(&NamedEnv {
+        packages: env.packages.clone(),
+    })
+}
+
+/// Installs the packages of `lock` that aren't in the store, e.g. after the
+/// project was checked out on another machine. These are exactly the resolved
+/// versions, dependencies aren't resolved again.
+pub fn install_project_lock(lock: &NamedEnvLock) -> Result<()> {
+    let installed = get_installed_packages_without_dpt_file()?;
+    let missing = lock
+        .resolved
+        .iter()
+        .filter(|x| package_to_onlinepackage(x, &installed).is_err())
+        .collect::<Vec<&Package>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    info!("Installing the packages of the project");
+    let available = get_available_packages_for(
+        &missing
+            .iter()
+            .map(|x| x.name.clone())
+            .collect::<Vec<String>>(),
+    )?;
+    for x in missing {
+        install_pkg(
+            &package_to_onlinepackage(x, &available).context(anyhow!(
+                "Package {}-{} of the lock file is not available!",
+                x.name,
+                x.version
+            ))?,
+            false,
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_project_lock(path: &Path, lock: &NamedEnvLock) -> Result<()> {
+    write_ron(&path.with_file_name(PROJECT_LOCK_FILE), lock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run::get_random_string;
+
+    #[test]
+    fn find_project_file_1() {
+        let dir = std::env::temp_dir()
+            .join(String::from("dpt-project-test-") + &get_random_string(10));
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(dir.join("a/b/c"))
+            .unwrap();
+        std::fs::write(dir.join("a").join(PROJECT_FILE), "").unwrap();
+        assert_eq!(
+            find_project_file(&dir.join("a/b/c")),
+            Some(dir.join("a").join(PROJECT_FILE))
+        );
+        assert_eq!(find_project_file(&dir), None);
+
+        let env = ProjectEnv {
+            features: vec!["unshare-net".into(), "memory=1G".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            feature_args(&env).unwrap(),
+            vec!["--unshare-net", "--memory", "1G"]
+        );
+        for features in [
+            vec!["fly"],
+            vec!["bind=/etc"],
+            vec!["keep-env=LD_PRELOAD"],
+            vec!["no-sandbox"],
+            vec!["memory"],
+            vec!["seccomp=1"],
+            vec!["memory=lots"],
+        ] {
+            let env = ProjectEnv {
+                features: features.into_iter().map(String::from).collect(),
+                ..Default::default()
+            };
+            assert!(feature_args(&env).is_err());
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
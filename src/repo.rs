@@ -1,4 +1,5 @@
 use crate::config::get_config_option;
+use crate::hash::sha256;
 use crate::pkg::Version;
 use anyhow::Context;
 use anyhow::{bail, Result};
@@ -9,22 +10,43 @@ use pubgrub::Ranges;
 use pubgrub::{DefaultStringReporter, Reporter};
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{self, Display};
 use std::fs::DirBuilder;
 use std::io::Read;
 use std::path::PathBuf;
 
-use crate::pkg::{self, Dependency, Package};
+use crate::pkg::{self, Dependency, Package, PackageConfig};
 use crate::store::get_store_location;
 
 type VersionSet = Ranges<Version>;
 
-#[derive(Debug, PartialEq, Clone, Hash, Eq, Serialize, Deserialize)]
+#[derive(
+    Debug, Default, PartialEq, Clone, Hash, Eq, Serialize, Deserialize,
+)]
 pub struct OnlinePackage {
     pub name: String,
     pub version: String,
     pub url: String,
     pub depends: Vec<Dependency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub license: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maintainers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub installed_size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download_size: Option<u64>,
+    /// SHA-256 of the `.dpt` file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// File names of the shared libraries that the package ships
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub provides: Vec<String>,
 }
 
 impl Display for OnlinePackage {
@@ -38,6 +60,23 @@ impl Display for OnlinePackage {
 }
 
 impl OnlinePackage {
+    /// Builds an OnlinePackage from a package configuration, keeping its metadata
+    pub fn from_package_config(cfg: PackageConfig, url: String) -> Self {
+        OnlinePackage {
+            name: cfg.name,
+            version: cfg.version,
+            url,
+            depends: cfg.depends,
+            description: cfg.description,
+            license: cfg.license,
+            homepage: cfg.homepage,
+            maintainers: cfg.maintainers,
+            installed_size: cfg.installed_size,
+            download_size: cfg.download_size,
+            ..Default::default()
+        }
+    }
+
     /// Consumes self
     pub fn to_package(self) -> Package {
         Package {
@@ -52,6 +91,24 @@ pub struct RepositoryIndex {
     pub packages: Vec<OnlinePackage>,
 }
 
+/// Maps shared library names to the names of the packages that ship them.
+/// Generated by `dpt gen-index` as `sonames.ron`.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SonameIndex {
+    pub sonames: BTreeMap<String, Vec<String>>,
+}
+
+/// The name of the file that lists the shards of a sharded repository
+pub const SHARD_MANIFEST: &str = "index.shards.ron";
+
+/// Describes how the index of a repository is split up. The packages whose
+/// names start with `prefix` are in `index/${prefix}.ron.zst`.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShardManifest {
+    pub prefix_length: usize,
+    pub shards: Vec<String>,
+}
+
 #[derive(Clone, Hash, PartialEq, Eq)]
 pub enum InstallResult {
     Installed,
@@ -76,7 +133,7 @@ pub fn get_repositories() -> Result<Vec<String>> {
 pub fn fetch_file(url: &str) -> Result<Vec<u8>> {
     let client = Client::new();
 
-    let response = client.get(url).send()?;
+    let response = client.get(url).send()?.error_for_status()?;
 
     let total_size = match response.content_length() {
         Some(x) => x,
@@ -139,16 +196,161 @@ pub fn parse_repository_index(
     Ok(doc.packages)
 }
 
+/// Gets the shard that a package name belongs to
+pub fn shard_prefix(name: &str, prefix_length: usize) -> String {
+    name.chars().take(prefix_length).collect()
+}
+
+/// Fetches and parses the full index of a repository, preferring the
+/// compressed `index.ron.zst` over `index.ron`
+fn fetch_repository_index(repo: &str) -> Result<Vec<OnlinePackage>> {
+    let index = match fetch_file(&push_onto_url(repo, "index.ron.zst")) {
+        Ok(x) => zstd::decode_all(&x[..])?,
+        Err(_) => fetch_file(&push_onto_url(repo, "index.ron"))?,
+    };
+    let index = std::str::from_utf8(&index)?;
+    parse_repository_index(index, repo)
+}
+
+/// Fetches the shard manifest of a repository, if it has one
+fn fetch_shard_manifest(repo: &str) -> Option<ShardManifest> {
+    let manifest = fetch_file(&push_onto_url(repo, SHARD_MANIFEST)).ok()?;
+    ron::from_str(std::str::from_utf8(&manifest).ok()?).ok()
+}
+
+/// Fetches the shards of a sharded repository that contain `names`, skipping
+/// the shards in `fetched`
+fn fetch_repository_shards(
+    repo: &str,
+    manifest: &ShardManifest,
+    names: &[String],
+    fetched: &mut HashSet<String>,
+) -> Result<Vec<OnlinePackage>> {
+    let mut ret = Vec::<OnlinePackage>::new();
+    for name in names {
+        let prefix = shard_prefix(name, manifest.prefix_length);
+        if !manifest.shards.contains(&prefix) || !fetched.insert(prefix.clone())
+        {
+            continue;
+        }
+        let shard = fetch_file(&push_onto_url(
+            repo,
+            &("index/".to_string() + &prefix + ".ron.zst"),
+        ))?;
+        let shard = zstd::decode_all(&shard[..])?;
+        ret.append(&mut parse_repository_index(
+            std::str::from_utf8(&shard)?,
+            repo,
+        )?);
+    }
+    Ok(ret)
+}
+
+struct RepositoryState {
+    url: String,
+    manifest: Option<ShardManifest>,
+    fetched: HashSet<String>,
+    packages: Vec<OnlinePackage>,
+}
+
+/// Gets the packages available on all repositories. If `names` is given,
+/// sharded repositories only fetch the shards needed to resolve those names
+/// and their dependencies.
+fn get_available_packages(
+    names: Option<&[String]>,
+) -> Result<Vec<OnlinePackage>> {
+    let mut repos = Vec::<RepositoryState>::new();
+    for repo in get_repositories()? {
+        let manifest = names.and_then(|_| fetch_shard_manifest(&repo));
+        let packages = match manifest {
+            Some(_) => vec![],
+            None => fetch_repository_index(&repo)?,
+        };
+        repos.push(RepositoryState {
+            url: repo,
+            manifest,
+            fetched: HashSet::new(),
+            packages,
+        });
+    }
+
+    if let Some(names) = names {
+        let mut seen: HashSet<String> = names.iter().cloned().collect();
+        let mut pending: Vec<String> = names.to_vec();
+        while !pending.is_empty() {
+            let batch = std::mem::take(&mut pending);
+            for repo in repos.iter_mut() {
+                if let Some(manifest) = &repo.manifest {
+                    let mut new = fetch_repository_shards(
+                        &repo.url,
+                        manifest,
+                        &batch,
+                        &mut repo.fetched,
+                    )?;
+                    repo.packages.append(&mut new);
+                }
+                // Dependencies may live in other shards or repositories
+                for pkg in
+                    repo.packages.iter().filter(|x| batch.contains(&x.name))
+                {
+                    for dep in &pkg.depends {
+                        if seen.insert(dep.name.clone()) {
+                            pending.push(dep.name.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(repos.into_iter().flat_map(|x| x.packages).collect())
+}
+
 /// Get all packages that are available on all repositories
 pub fn get_all_available_packages() -> Result<Vec<OnlinePackage>> {
+    get_available_packages(None)
+}
+
+/// Get the packages needed to resolve `names` from all repositories
+pub fn get_available_packages_for(
+    names: &[String],
+) -> Result<Vec<OnlinePackage>> {
+    get_available_packages(Some(names))
+}
+
+/// Merges the soname maps of all repositories. Earlier repositories come first
+/// in the list of providers. Repositories without a map are skipped.
+pub fn get_soname_index() -> Result<SonameIndex> {
     let repos = get_repositories()?;
 
-    let mut ret: Vec<OnlinePackage> = Vec::new();
+    let mut ret = SonameIndex::default();
     for repo in repos {
-        let index = fetch_file(&push_onto_url(repo.as_str(), "index.ron"))?;
-        let index = std::str::from_utf8(&index)?;
-        let mut packages = parse_repository_index(index, &repo)?;
-        ret.append(&mut packages);
+        let index =
+            match fetch_file(&push_onto_url(repo.as_str(), "sonames.ron")) {
+                Ok(x) => x,
+                Err(x) => {
+                    log::warn!("Failed to fetch the soname map of {repo}: {x}");
+                    continue;
+                }
+            };
+        let index: SonameIndex = match std::str::from_utf8(&index)
+            .map_err(anyhow::Error::from)
+            .and_then(|x| Ok(ron::from_str(x)?))
+        {
+            Ok(x) => x,
+            Err(x) => {
+                log::warn!("Failed to read the soname map of {repo}: {x}");
+                continue;
+            }
+        };
+        for (soname, providers) in index.sonames {
+            let entry = ret.sonames.entry(soname).or_default();
+            for p in providers {
+                if !entry.contains(&p) {
+                    entry.push(p);
+                }
+            }
+        }
     }
 
     Ok(ret)
@@ -159,7 +361,7 @@ pub fn parse_version_range(vr: &str) -> Result<Ranges<Version>> {
     Ok(if vr.len() < 1 {
         VersionSet::full()
     } else if vr.chars().next() == Some('>') {
-        if vr.chars().nth(1).unwrap() == '=' {
+        if vr.chars().nth(1) == Some('=') {
             VersionSet::higher_than(Version::from_str(&vr[2..])?)
         } else {
             Ranges::higher_than(Version::from_str(&vr[1..])?.bump())
@@ -321,6 +523,7 @@ pub fn install_pkg(
     }
 
     let file = fetch_file(&pkg.url)?;
+    verify_package(pkg, &file)?;
 
     let mut archive = pkg::decompress_pkg_read(&file[..])?;
 
@@ -334,6 +537,29 @@ pub fn install_pkg(
     Ok(InstallResult::Installed)
 }
 
+/// Checks that `data` is the `.dpt` file that the index entry of `pkg`
+/// describes. Packages are unpacked as root with their owners and modes, so
+/// ones without a `sha256` are refused too.
+fn verify_package(pkg: &OnlinePackage, data: &[u8]) -> Result<()> {
+    let Some(expected) = &pkg.sha256 else {
+        bail!(
+            "The index has no sha256 for {}-{}, refusing to install it!",
+            pkg.name,
+            pkg.version
+        );
+    };
+    let got = sha256(data);
+    if !got.eq_ignore_ascii_case(expected) {
+        bail!(
+            "{}-{} from {} has the sha256 {got} instead of {expected}, refusing to install it!",
+            pkg.name,
+            pkg.version,
+            pkg.url
+        );
+    }
+    Ok(())
+}
+
 /// Install a package and all of it's dependencies into the pool
 pub fn install_pkgs_and_dependencies(
     pkgs_selected: &Vec<OnlinePackage>,
@@ -360,6 +586,28 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn verify_package_1() {
+        let data = b"(name: \"a\", version: \"1.0\")".to_vec();
+        let mut pkg = OnlinePackage {
+            name: "a".to_string(),
+            version: "1.0".to_string(),
+            url: "https://my.repo.here/dpt/a-1.0.dpt".to_string(),
+            ..Default::default()
+        };
+        // No sha256 in the index
+        assert!(verify_package(&pkg, &data).is_err());
+
+        pkg.sha256 = Some(sha256(&data));
+        assert!(verify_package(&pkg, &data).is_ok());
+        pkg.sha256 = Some(sha256(&data).to_uppercase());
+        assert!(verify_package(&pkg, &data).is_ok());
+
+        let mut tampered = data.clone();
+        tampered[1] ^= 1;
+        assert!(verify_package(&pkg, &tampered).is_err());
+    }
+
     #[test]
     fn parse_repository_index_1() {
         let index = r###"
@@ -397,6 +645,7 @@ mod tests {
                 version: "9.11.14".to_string(),
                 url: "https://my.repo.here/dpt/test.dpt".to_string(),
                 depends: Vec::<Dependency>::new(),
+                ..Default::default()
             },
             OnlinePackage {
                 name: "example".to_string(),
@@ -412,12 +661,49 @@ mod tests {
                         version: "^10.2.0".to_string(),
                     },
                 ],
+                ..Default::default()
             },
         ];
 
         assert_eq!(x, expected);
     }
 
+    #[test]
+    fn parse_repository_index_metadata() {
+        let index = r###"
+(
+    packages: [
+        (
+            name: "test",
+            version: "9.11.14",
+            url: "/test.dpt",
+            depends: [],
+            description: Some("A test package"),
+            license: Some("GPL-3.0-or-later"),
+            maintainers: ["Someone"],
+            installed_size: Some(1234),
+            download_size: Some(567)
+        )
+    ]
+)
+            "###;
+        let x =
+            parse_repository_index(index, "https://my.repo.here/dpt").unwrap();
+        assert_eq!(x[0].description, Some("A test package".to_string()));
+        assert_eq!(x[0].license, Some("GPL-3.0-or-later".to_string()));
+        assert_eq!(x[0].homepage, None);
+        assert_eq!(x[0].maintainers, vec!["Someone".to_string()]);
+        assert_eq!(x[0].installed_size, Some(1234));
+        assert_eq!(x[0].download_size, Some(567));
+    }
+
+    #[test]
+    fn test_shard_prefix() {
+        assert_eq!(shard_prefix("python", 2), "py");
+        assert_eq!(shard_prefix("x", 2), "x");
+        assert_eq!(shard_prefix("äbc", 1), "ä");
+    }
+
     #[test]
     fn resolve_1() {
         let packages = vec![
@@ -426,6 +712,7 @@ mod tests {
                 version: "1.2.3".to_string(),
                 url: "https://my.repo.pkg/dpt/1.dpt".to_string(),
                 depends: vec![],
+                ..Default::default()
             },
             OnlinePackage {
                 name: "2".to_string(),
@@ -435,6 +722,7 @@ mod tests {
                     name: "1".to_string(),
                     version: ">=1.0.0".to_string(),
                 }],
+                ..Default::default()
             },
             OnlinePackage {
                 name: "goal".to_string(),
@@ -444,6 +732,7 @@ mod tests {
                     name: "2".to_string(),
                     version: ">4.5.0".to_string(),
                 }],
+                ..Default::default()
             },
         ];
 
@@ -1,11 +1,25 @@
-use log::error;
-use nix::mount::MsFlags;
+use log::{error, warn};
+use nix::{
+    errno::Errno,
+    fcntl::{open, openat, OFlag},
+    mount::MsFlags,
+    sched::{unshare, CloneFlags},
+    sys::{
+        stat::{fstat, mkdirat, Mode, SFlag},
+        statvfs::FsFlags,
+    },
+    unistd::{getpgrp, Pid},
+};
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     fs::File,
     io::{BufRead, BufReader},
-    os::unix::process::CommandExt,
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::process::CommandExt,
+    },
+    path::{Component, Path, PathBuf},
 };
 
 use anyhow::{bail, Context, Result};
@@ -13,14 +27,89 @@ use rand::prelude::*;
 use sys_mount::{unmount, UnmountFlags};
 
 use crate::{
+    binds::{is_empty_dir, plan_binds, BindPlan, BindRule},
+    cgroup::{parse_size, Limits},
+    creds::Credentials,
+    dpt_file::read_dpt_lock_file,
+    env::Environment,
+    envvars::program_vars,
+    named_env::{locked_packages, NamedEnvLock},
     pkg::Package,
-    store::{get_installed_packages, get_installed_packages_without_dpt_file},
+    sandbox::Sandbox,
+    signals,
+    store::{
+        get_dpt_dir, get_installed_packages,
+        get_installed_packages_without_dpt_file, is_rootless,
+    },
 };
 
+/// Removes a directory that is being set up when it is dropped, e.g. because
+/// setting it up failed. Once the directory has been moved into place there is
+/// nothing left to remove.
+pub struct DirGuard {
+    path: PathBuf,
+}
+
+impl DirGuard {
+    pub fn new(path: PathBuf) -> DirGuard {
+        DirGuard { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DirGuard {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            if let Err(x) = std::fs::remove_dir_all(&self.path) {
+                warn!("Failed to remove {}: {x}", self.path.display());
+            }
+        }
+    }
+}
+
+/// Unmounts bind mounts when it is dropped
+pub struct MountGuard {
+    mounts: Vec<PathBuf>,
+    /// The mounts can't be unmounted, so they are left alone
+    locked: bool,
+}
+
+impl MountGuard {
+    pub fn new(locked: bool) -> MountGuard {
+        MountGuard {
+            mounts: Vec::new(),
+            locked,
+        }
+    }
+
+    pub fn push(&mut self, mount: PathBuf) {
+        self.mounts.push(mount);
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        if self.locked {
+            return;
+        }
+        for mount in self.mounts.iter().rev() {
+            if !mount.exists() {
+                continue; // Not reachable after pivot_root
+            }
+            if let Err(x) = unmount_recursive(mount) {
+                warn!("Failed to unmount {}: {x}", mount.display());
+            }
+        }
+    }
+}
+
 pub fn get_run_location() -> PathBuf {
     match crate::config::get_config_option(&"run".to_string()) {
         Some(x) => PathBuf::from(x),
-        None => PathBuf::from("/dpt/run/"),
+        None => get_dpt_dir().join("run"),
     }
 }
 
@@ -118,7 +207,10 @@ pub fn bind_mount(src: &Path, target: &Path, recursive: bool) -> Result<()> {
         std::fs::DirBuilder::new()
             .recursive(true)
             .create(&target.parent().unwrap_or(Path::new("/")))?;
-        std::fs::File::create(&target)?;
+        // Don't truncate what is there, it may be hard linked to the store
+        if !target.exists() {
+            std::fs::File::create(&target)?;
+        }
     }
     match bind_mount_(&src, &target, recursive) {
         Err(x) => bail!(x.to_string()),
@@ -126,6 +218,347 @@ pub fn bind_mount(src: &Path, target: &Path, recursive: bool) -> Result<()> {
     }
 }
 
+/// Directories inside of the dpt directory that are read-only in environments.
+/// `base` and `run` hold hard links to files in the store.
+pub const READ_ONLY_DIRS: [&str; 3] = ["store", "base", "run"];
+
+/// Mounts `path` read-only on top of itself
+pub fn bind_mount_read_only(path: &Path) -> Result<()> {
+    bind_mount(path, path, false)?;
+    nix::mount::mount(
+        Option::<&Path>::None,
+        path,
+        Option::<&Path>::None,
+        MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+        Option::<&Path>::None,
+    )
+    .context(anyhow::anyhow!(
+        "Failed to remount {} read-only",
+        path.display()
+    ))?;
+    Ok(())
+}
+
+/// Moves into a new user and mount namespace where the current user is mapped
+/// to themselves, which gives the capabilities needed to set up environments
+/// without root.
+pub fn enter_user_namespace() -> Result<()> {
+    let uid = uzers::get_current_uid();
+    let gid = uzers::get_current_gid();
+    unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)
+        .context("Failed to create a user namespace")?;
+    std::fs::write("/proc/self/setgroups", "deny")?;
+    std::fs::write("/proc/self/uid_map", format!("{uid} {uid} 1"))?;
+    std::fs::write("/proc/self/gid_map", format!("{gid} {gid} 1"))?;
+    Ok(())
+}
+
+/// Environments mount parts of the dpt directory read-only, so dpt needs a
+/// mount namespace without those mounts to create environments from inside of
+/// one.
+pub fn make_dpt_dir_writable() -> Result<()> {
+    match nix::sys::statvfs::statvfs(&get_run_location()) {
+        Ok(x) if x.flags().contains(FsFlags::ST_RDONLY) => {}
+        _ => return Ok(()),
+    }
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    nix::mount::mount(
+        Option::<&Path>::None,
+        "/",
+        Option::<&Path>::None,
+        MsFlags::MS_REC | MsFlags::MS_SLAVE,
+        Option::<&Path>::None,
+    )?;
+    // Hard links can't cross mounts, so remove them instead of remounting
+    for dir in READ_ONLY_DIRS {
+        let _ = unmount(get_dpt_dir().join(dir), UnmountFlags::DETACH);
+    }
+    Ok(())
+}
+
+/// Options for running a program that are given on the command line
+#[derive(Debug, Default, Clone)]
+pub struct RunOptions {
+    /// Variables to pass through from the caller
+    pub keep_env: Vec<String>,
+    /// `--bind` and `--no-bind`, in order
+    pub binds: Vec<BindRule>,
+    /// What was turned on with `--sandbox` and its fine-grained flags
+    pub sandbox: Sandbox,
+    /// Don't use the sandbox that the packages ask for
+    pub no_sandbox: bool,
+    pub limits: Limits,
+    /// Variables to set, which win over the ones of the packages
+    pub vars: BTreeMap<String, String>,
+}
+
+/// What the second stage sets up, passed to it as RON
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct StageOptions {
+    /// `lowerdir` of overlay environments, empty for hard linked ones
+    pub lowerdir: String,
+    pub binds: BindPlan,
+    /// Set `PR_SET_NO_NEW_PRIVS` on the program
+    pub no_new_privs: bool,
+    pub sandbox: Sandbox,
+    pub limits: Limits,
+}
+
+/// Takes the run options out of `args`, returning the rest. Options after `--`
+/// are left alone, and with `leading_only` so is everything after the first
+/// other argument.
+pub fn take_run_options(
+    args: &[String],
+    leading_only: bool,
+) -> Result<(RunOptions, Vec<String>)> {
+    let mut opts = RunOptions::default();
+    let mut rest = Vec::<String>::new();
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--keep-env" => {
+                i += 1;
+                opts.keep_env.push(
+                    args.get(i).context("--keep-env needs a variable")?.clone(),
+                );
+            }
+            "--bind" => {
+                i += 1;
+                let bind = args.get(i).context("--bind needs a path")?;
+                opts.binds.push(BindRule::Bind(bind.parse()?));
+            }
+            "--no-bind" => {
+                i += 1;
+                let path = args.get(i).context("--no-bind needs a path")?;
+                opts.binds.push(BindRule::no_bind(path)?);
+            }
+            "--sandbox" => opts.sandbox = opts.sandbox.union(Sandbox::full()),
+            "--no-sandbox" => opts.no_sandbox = true,
+            "--unshare-pid" => opts.sandbox.pid = true,
+            "--unshare-net" => opts.sandbox.net = true,
+            "--unshare-ipc" => opts.sandbox.ipc = true,
+            "--unshare-uts" => opts.sandbox.uts = true,
+            "--seccomp" => opts.sandbox.seccomp = true,
+            "--hide-home" => opts.sandbox.hide_home = true,
+            "--memory" => {
+                i += 1;
+                let size = args.get(i).context("--memory needs a size")?;
+                opts.limits.memory = Some(parse_size(size)?);
+            }
+            "--cpus" => {
+                i += 1;
+                let cpus = args.get(i).context("--cpus needs a number")?;
+                opts.limits.cpus = Some(
+                    cpus.parse()
+                        .context(anyhow::anyhow!("Invalid --cpus '{cpus}'"))?,
+                );
+            }
+            "--pids-max" => {
+                i += 1;
+                let max = args.get(i).context("--pids-max needs a number")?;
+                opts.limits.pids_max =
+                    Some(max.parse().context(anyhow::anyhow!(
+                        "Invalid --pids-max '{max}'"
+                    ))?);
+            }
+            "--io-weight" => {
+                i += 1;
+                let weight =
+                    args.get(i).context("--io-weight needs a number")?;
+                opts.limits.io_weight = Some(weight.parse().context(
+                    anyhow::anyhow!("Invalid --io-weight '{weight}'"),
+                )?);
+            }
+            "--" => {
+                rest.extend_from_slice(&args[i..]);
+                break;
+            }
+            x => {
+                if leading_only {
+                    rest.extend_from_slice(&args[i..]);
+                    break;
+                }
+                rest.push(x.to_string());
+            }
+        }
+        i += 1;
+    }
+    opts.limits.check()?;
+    Ok((opts, rest))
+}
+
+/// Gets a path that refers to what `fd` is open on
+pub fn fd_path(fd: &OwnedFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// Opens `path` inside of `root` one component at a time without following
+/// symlinks, so that nothing in the environment can point a mount outside of
+/// it. If `create` is set, missing components are created as directories,
+/// except for the last one which is an empty file unless `dir` is set.
+/// Returns `None` if `path` doesn't exist and isn't created.
+pub fn open_in_root(
+    root: &Path,
+    path: &Path,
+    create: bool,
+    dir: bool,
+) -> Result<Option<OwnedFd>> {
+    let flags = OFlag::O_PATH | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let mut fd = open(root, flags | OFlag::O_DIRECTORY, Mode::empty())?;
+    let names = path
+        .components()
+        .filter_map(|x| match x {
+            Component::Normal(x) => Some(Ok(x)),
+            Component::RootDir | Component::CurDir => None,
+            _ => Some(Err(anyhow::anyhow!(
+                "Invalid path {} in environment",
+                path.display()
+            ))),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for (i, name) in names.iter().enumerate() {
+        let last = i + 1 == names.len();
+        let next = match openat(&fd, *name, flags, Mode::empty()) {
+            Ok(x) => x,
+            Err(Errno::ENOENT) if create => {
+                if last && !dir {
+                    openat(
+                        &fd,
+                        *name,
+                        OFlag::O_CREAT
+                            | OFlag::O_EXCL
+                            | OFlag::O_WRONLY
+                            | OFlag::O_NOFOLLOW
+                            | OFlag::O_CLOEXEC,
+                        Mode::from_bits_truncate(0o644),
+                    )?;
+                } else {
+                    mkdirat(&fd, *name, Mode::from_bits_truncate(0o755))?;
+                }
+                openat(&fd, *name, flags, Mode::empty())?
+            }
+            Err(Errno::ENOENT) => return Ok(None),
+            Err(x) => bail!("Failed to open {}: {x}", path.display()),
+        };
+        let kind =
+            SFlag::from_bits_truncate(fstat(&next)?.st_mode) & SFlag::S_IFMT;
+        if kind == SFlag::S_IFLNK {
+            bail!(
+                "{} leads through a symlink in the environment",
+                path.display()
+            );
+        }
+        fd = next;
+    }
+    Ok(Some(fd))
+}
+
+/// Mounts the binds and masks of `plan` into `out_dir`, adding the binds to
+/// `mounts`. Sources are opened as `creds`, and mountpoints are only created
+/// if `create` is set, since a hard linked environment is shared with other
+/// runs.
+pub fn apply_bind_plan(
+    out_dir: &Path,
+    plan: &BindPlan,
+    creds: &Credentials,
+    create: bool,
+    mounts: &mut MountGuard,
+) -> Result<()> {
+    for bind in &plan.binds {
+        let src = match creds.open_path(&bind.src) {
+            Ok(x) => x,
+            Err(x) => {
+                if !bind.weak {
+                    warn!("Not binding {bind}, {}: {x}", bind.src.display());
+                }
+                continue;
+            }
+        };
+        let dir = SFlag::from_bits_truncate(fstat(&src)?.st_mode)
+            & SFlag::S_IFMT
+            == SFlag::S_IFDIR;
+        let target = match open_in_root(out_dir, &bind.dst, false, dir) {
+            Ok(x) => x,
+            // The environment has something of its own there
+            Err(_) if bind.weak => continue,
+            Err(x) => return Err(x),
+        };
+        let target = match target {
+            // Empty directories are mountpoints, made when the environment
+            // was generated
+            Some(x) if bind.weak && !is_empty_dir(&fd_path(&x)) => continue,
+            Some(x) => x,
+            None if create => open_in_root(out_dir, &bind.dst, true, dir)?
+                .context("Failed to create mountpoint")?,
+            None => {
+                if !bind.weak {
+                    warn!(
+                        "Not binding {bind}, {} doesn't exist in the \
+                        environment",
+                        bind.dst.display()
+                    );
+                }
+                continue;
+            }
+        };
+        nix::mount::mount(
+            Some(&fd_path(&src)),
+            &fd_path(&target),
+            Option::<&Path>::None,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            Option::<&Path>::None,
+        )
+        .context(anyhow::anyhow!("Failed to bind {bind}"))?;
+        // Opened again to get the mount instead of what is under it
+        let target = open_in_root(out_dir, &bind.dst, false, dir)?
+            .context(anyhow::anyhow!("Failed to bind {bind}"))?;
+        let mut flags = vec![MsFlags::MS_SLAVE | MsFlags::MS_REC];
+        if bind.read_only {
+            flags.push(
+                MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+            );
+        }
+        for x in flags {
+            nix::mount::mount(
+                Option::<&Path>::None,
+                &fd_path(&target),
+                Option::<&Path>::None,
+                x,
+                Option::<&Path>::None,
+            )
+            .context(anyhow::anyhow!("Failed to bind {bind}"))?;
+        }
+        mounts.push(join_proper(out_dir, &bind.dst)?);
+    }
+
+    for mask in &plan.masks {
+        let Some(target) = open_in_root(out_dir, mask, false, false)
+            .context(anyhow::anyhow!("Failed to hide {}", mask.display()))?
+        else {
+            continue;
+        };
+        let target = fd_path(&target);
+        if target.is_dir() {
+            nix::mount::mount(
+                Some("tmpfs"),
+                &target,
+                Some("tmpfs"),
+                MsFlags::MS_RDONLY,
+                Some("mode=0755"),
+            )?;
+        } else {
+            nix::mount::mount(
+                Some("/dev/null"),
+                &target,
+                Option::<&Path>::None,
+                MsFlags::MS_BIND,
+                Option::<&Path>::None,
+            )?;
+        }
+    }
+    Ok(())
+}
+
 pub fn run_pkg(
     pkg: &Package,
     uid: u32,
@@ -133,6 +566,7 @@ pub fn run_pkg(
     cmd: Option<&str>,
     allow_non_dpt_file: bool,
     replace_current_process: bool,
+    opts: &RunOptions,
 ) -> Result<i32> {
     run_multiple_packages(
         &vec![pkg.clone()],
@@ -141,75 +575,173 @@ pub fn run_pkg(
         cmd,
         allow_non_dpt_file,
         replace_current_process,
+        opts,
     )
 }
 
+/// Escapes a path for use in overlayfs mount options
+fn escape_overlay_path(p: &Path) -> String {
+    p.to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace(':', "\\:")
+        .replace(',', "\\,")
+}
+
+/// Gets the `lowerdir` overlayfs option for a list of layers
+pub fn overlay_lowerdir(layers: &[PathBuf]) -> String {
+    layers
+        .iter()
+        .map(|x| escape_overlay_path(x))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+/// Mounts a tmpfs on `dir` and an overlay of `lowerdir` inside of it, using
+/// the tmpfs as the upper layer. Returns where the merged tree is mounted.
+pub fn mount_overlay(dir: &Path, lowerdir: &str) -> Result<PathBuf> {
+    nix::mount::mount(
+        Some("tmpfs"),
+        dir,
+        Some("tmpfs"),
+        MsFlags::empty(),
+        Some("mode=0755"),
+    )
+    .context("Failed to mount tmpfs for overlay")?;
+    for x in ["upper", "work", "root"] {
+        std::fs::DirBuilder::new().create(dir.join(x))?;
+    }
+    let data = format!(
+        "lowerdir={lowerdir},upperdir={},workdir={}",
+        escape_overlay_path(&dir.join("upper")),
+        escape_overlay_path(&dir.join("work"))
+    );
+    // The kernel only reads one page of mount options
+    if data.len() >= 4096 {
+        bail!(
+            "Too many packages for the overlay backend, use the `hardlink` \
+            backend instead"
+        );
+    }
+    let root = dir.join("root");
+    nix::mount::mount(
+        Some("overlay"),
+        &root,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(data.as_str()),
+    )
+    .context("Failed to mount overlay")?;
+    Ok(root)
+}
+
 pub fn run_pkg_(
-    out_dir: &Path,
+    env: &Environment,
     uid: u32,
     args: Vec<String>,
     cmd: &str,
     replace_current_process: bool,
+    opts: &RunOptions,
 ) -> Result<i32> {
-    let mut cleanup = false;
-
-    let mut prefix = "/";
-    if out_dir.join("bin").join(&cmd).is_file()
-        || out_dir.join("bin").join(&cmd).is_symlink()
-    {
-        prefix = "/bin";
-    } else if out_dir.join("usr/bin").join(&cmd).is_file()
-        || out_dir.join("usr/bin").join(&cmd).is_symlink()
-    {
-        prefix = "/usr/bin";
+    let out_dir = &env.path;
+
+    let mut rules = Vec::<BindRule>::new();
+    if let Ok(dpt) = read_dpt_lock_file() {
+        rules.extend(BindRule::from_lists(&dpt.binds, &dpt.no_binds)?);
+    }
+    rules.extend(env.bind_rules.iter().cloned());
+    let sandbox = if env.sandbox && !opts.no_sandbox {
+        opts.sandbox.union(Sandbox::full())
     } else {
-        error!("No executable found!");
-        cleanup = true;
+        opts.sandbox
+    };
+    if sandbox.hide_home {
+        rules.push(BindRule::NoBind(PathBuf::from("/home")));
     }
-    let mut code: i32 = 0;
-    if !cleanup {
-        let mut proc = std::process::Command::new(
-            std::env::current_exe().unwrap_or(PathBuf::from("/dpt/dpt")),
-        );
-        let proc = proc
-            .arg("run-pkg-second-stage-not-intended-for-interactive-use")
-            .arg(&out_dir.to_str().ok_or(anyhow::anyhow!(
-                "Failed to parse directory {} into string!",
-                &out_dir.display()
-            ))?)
-            .arg(uid.to_string())
-            .arg(Path::new(prefix).join(&cmd))
-            .arg(if replace_current_process {
-                "replace"
-            } else {
-                "new"
-            })
-            .args(args);
-        if replace_current_process {
-            let err = proc.exec();
-            bail!("Failed to run process! Error: {err}");
+    rules.extend(opts.binds.iter().cloned());
+    let mut binds = plan_binds(&rules);
+    // A PID namespace gets its own proc
+    if sandbox.pid {
+        binds.binds.retain(|x| x.dst != Path::new("/proc"));
+    }
+    // Binds from the caller could be used to fool setuid programs in the
+    // environment
+    let caller_binds =
+        opts.binds.iter().any(|x| matches!(x, BindRule::Bind(_)));
+    let stage = StageOptions {
+        lowerdir: overlay_lowerdir(&env.layers),
+        binds,
+        no_new_privs: caller_binds && !is_rootless() && uid != 0,
+        sandbox,
+        limits: opts.limits.or(env.limits),
+    };
+    let mut vars = env.vars.clone();
+    vars.extend(opts.vars.clone());
+
+    let prefix = if env.contains(&Path::new("bin").join(&cmd)) {
+        "/bin"
+    } else if env.contains(&Path::new("usr/bin").join(&cmd)) {
+        "/usr/bin"
+    } else {
+        error!("No executable {cmd} found!");
+        // What a shell returns for commands that it can't find
+        return Ok(127);
+    };
+    let mut proc = std::process::Command::new(
+        std::env::current_exe().unwrap_or(PathBuf::from("/dpt/dpt")),
+    );
+    let proc = proc
+        .arg("run-pkg-second-stage-not-intended-for-interactive-use")
+        .arg(&out_dir.to_str().ok_or(anyhow::anyhow!(
+            "Failed to parse directory {} into string!",
+            &out_dir.display()
+        ))?)
+        .arg(uid.to_string())
+        .arg(Path::new(prefix).join(&cmd))
+        .arg(if replace_current_process {
+            "replace"
         } else {
-            let proc: Arc<Mutex<std::process::Child>> =
-                Arc::new(Mutex::new(proc.spawn()?));
-
-            let proc_arc_clone = Arc::clone(&proc);
-            ctrlc::set_handler(move || {
-                let l = proc_arc_clone.lock();
-                if let Ok(mut x) = l {
-                    let _ = x.kill();
-                };
-            })
-            .context("Failed to register ctrlc signal handler")?;
-            let l = proc.lock();
-            if let Ok(mut x) = l {
-                code = x.wait()?.code().unwrap_or(89);
-            } else {
-                code = 243;
-            }
-        }
+            "new"
+        })
+        .arg(ron::to_string(&stage)?)
+        .args(args)
+        .env_clear()
+        .envs(program_vars(std::env::vars_os(), &vars, &opts.keep_env));
+    if replace_current_process {
+        let err = proc.exec();
+        bail!("Failed to run process! Error: {err}");
+    }
+    // The program gets its own process group, which is put in the foreground
+    // of the terminal if we are
+    signals::block_signals()?;
+    let tty = signals::foreground_tty();
+    proc.process_group(0);
+    unsafe {
+        proc.pre_exec(move || {
+            signals::give_terminal(tty, getpgrp())?;
+            Ok(())
+        });
     }
+    let child = Pid::from_raw(proc.spawn()?.id() as i32);
+    signals::wait_foreground(child, tty)
+}
 
-    Ok(code)
+/// Runs `cmd` in the environment of a named or project environment's lock
+pub fn run_locked_packages(
+    lock: &NamedEnvLock,
+    uid: u32,
+    args: Vec<String>,
+    cmd: &str,
+    opts: &RunOptions,
+) -> Result<i32> {
+    if lock.packages.is_empty() {
+        bail!("No packages specified!");
+    }
+    let env = crate::env::get_environment_for_packages(
+        &lock.packages,
+        &locked_packages(lock, &get_installed_packages_without_dpt_file()?)?,
+        true,
+    )?;
+    run_pkg_(&env, uid, args, cmd, false, opts)
 }
 
 pub fn run_multiple_packages(
@@ -219,34 +751,25 @@ pub fn run_multiple_packages(
     cmd: Option<&str>,
     allow_non_dpt_file: bool,
     replace_current_process: bool,
+    opts: &RunOptions,
 ) -> Result<i32> {
     if pkgs.is_empty() {
         bail!("No packages specified!");
     }
 
-    let mut pkg_path = get_run_location().join(get_random_string(10));
-    while pkg_path.exists() {
-        pkg_path = get_run_location().join(get_random_string(10));
-    }
-    let pkg_path = pkg_path;
-
     let installed_packages = if allow_non_dpt_file == false {
         get_installed_packages()?
     } else {
         get_installed_packages_without_dpt_file()?
     };
 
-    crate::env::generate_environment_for_packages(
+    let env = crate::env::get_environment_for_packages(
         pkgs,
         &installed_packages,
-        &pkg_path,
         allow_non_dpt_file,
     )?;
 
     let cmd = cmd.unwrap_or(&pkgs[0].name);
 
-    let code = run_pkg_(&pkg_path, uid, args, cmd, replace_current_process)?;
-    std::fs::remove_dir_all(pkg_path)?;
-
-    Ok(code)
+    run_pkg_(&env, uid, args, cmd, replace_current_process, opts)
 }
@@ -0,0 +1,310 @@
+use std::{
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use nix::{
+    mount::MsFlags,
+    sched::CloneFlags,
+    unistd::{fork, ForkResult},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::signals;
+
+/// Extra isolation for a program on top of its environment
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub struct Sandbox {
+    /// Own PID namespace, with dpt as its init
+    pub pid: bool,
+    /// Own network namespace with only a loopback interface
+    pub net: bool,
+    pub ipc: bool,
+    pub uts: bool,
+    /// Apply the default seccomp filter
+    pub seccomp: bool,
+    /// Don't bind `/home` by default
+    pub hide_home: bool,
+}
+
+impl Sandbox {
+    /// The `--sandbox` profile
+    pub fn full() -> Sandbox {
+        Sandbox {
+            pid: true,
+            net: true,
+            ipc: true,
+            uts: true,
+            seccomp: true,
+            hide_home: true,
+        }
+    }
+
+    /// Enables everything that is enabled in either
+    pub fn union(self, other: Sandbox) -> Sandbox {
+        Sandbox {
+            pid: self.pid || other.pid,
+            net: self.net || other.net,
+            ipc: self.ipc || other.ipc,
+            uts: self.uts || other.uts,
+            seccomp: self.seccomp || other.seccomp,
+            hide_home: self.hide_home || other.hide_home,
+        }
+    }
+
+    /// The namespaces to unshare on top of the mount namespace
+    pub fn clone_flags(&self) -> CloneFlags {
+        let mut flags = CloneFlags::empty();
+        flags.set(CloneFlags::CLONE_NEWPID, self.pid);
+        flags.set(CloneFlags::CLONE_NEWNET, self.net);
+        flags.set(CloneFlags::CLONE_NEWIPC, self.ipc);
+        flags.set(CloneFlags::CLONE_NEWUTS, self.uts);
+        flags
+    }
+}
+
+/// Forks after the PID namespace was unshared, since only children end up in
+/// it. The parent waits for the child and gets its exit code, while the child
+/// gets `None` and carries on as the init of the namespace. Signals have to be
+/// blocked already.
+pub fn fork_into_pid_namespace() -> Result<Option<i32>> {
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => {
+            Ok(Some(signals::wait_in_group(child, true)?))
+        }
+        ForkResult::Child => Ok(None),
+    }
+}
+
+/// Mounts a `proc` for the current PID namespace at `target`
+pub fn mount_proc(target: &Path) -> Result<()> {
+    std::fs::DirBuilder::new().recursive(true).create(target)?;
+    nix::mount::mount(
+        Some("proc"),
+        target,
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Option::<&Path>::None,
+    )
+    .context("Failed to mount proc")?;
+    Ok(())
+}
+
+/// Brings up the loopback interface of a new network namespace
+pub fn loopback_up() -> Result<()> {
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_INET,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            0,
+        );
+        if fd < 0 {
+            bail!(
+                "Failed to create socket: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let fd = OwnedFd::from_raw_fd(fd);
+        let mut req: libc::ifreq = std::mem::zeroed();
+        for (i, c) in b"lo".iter().enumerate() {
+            req.ifr_name[i] = *c as libc::c_char;
+        }
+        if libc::ioctl(fd.as_raw_fd(), libc::SIOCGIFFLAGS as _, &mut req) < 0 {
+            bail!(
+                "Failed to get flags of lo: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        req.ifr_ifru.ifru_flags |= libc::IFF_UP as libc::c_short;
+        if libc::ioctl(fd.as_raw_fd(), libc::SIOCSIFFLAGS as _, &req) < 0 {
+            bail!("Failed to bring up lo: {}", std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The `AUDIT_ARCH_*` value of the architecture, if the seccomp filter
+/// supports it
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc00000b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// Syscalls that the default filter denies with `EPERM`. They change the
+/// system, the sandbox itself or other processes.
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_adjtimex,
+    libc::SYS_bpf,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fsopen,
+    libc::SYS_fspick,
+    libc::SYS_init_module,
+    libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_mount_setattr,
+    libc::SYS_move_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_open_tree,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setns,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_syslog,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+    libc::SYS_userfaultfd,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+];
+
+/// `clone` flags that create namespaces
+const NAMESPACE_FLAGS: u32 = (libc::CLONE_NEWNS
+    | libc::CLONE_NEWUTS
+    | libc::CLONE_NEWIPC
+    | libc::CLONE_NEWUSER
+    | libc::CLONE_NEWPID
+    | libc::CLONE_NEWNET
+    | libc::CLONE_NEWCGROUP) as u32;
+
+/// A seccomp BPF program
+pub struct SeccompFilter(Vec<libc::sock_filter>);
+
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+impl SeccompFilter {
+    /// Denies [`DENIED_SYSCALLS`], and `clone` with namespace flags. `clone3`
+    /// fails with `ENOSYS`, since its flags can't be checked, which makes libc
+    /// fall back to `clone`. Other architectures and x32 syscalls are killed.
+    /// Fails on architectures that the filter doesn't support.
+    pub fn default_filter() -> Result<SeccompFilter> {
+        use libc::{
+            BPF_ABS, BPF_ALU, BPF_AND, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD,
+            BPF_RET, BPF_W, EPERM, SECCOMP_RET_ALLOW, SECCOMP_RET_DATA,
+            SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
+        };
+        // Offsets into struct seccomp_data
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+        const ARG0: u32 = 16;
+        let Some(audit_arch) = AUDIT_ARCH else {
+            bail!("The seccomp filter is not supported on this architecture!");
+        };
+        let deny = SECCOMP_RET_ERRNO | (EPERM as u32 & SECCOMP_RET_DATA);
+
+        let mut f = vec![
+            bpf_stmt(BPF_LD | BPF_W | BPF_ABS, ARCH),
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, audit_arch, 1, 0),
+            bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            bpf_stmt(BPF_LD | BPF_W | BPF_ABS, NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        f.extend([
+            bpf_jump(BPF_JMP | libc::BPF_JGE | BPF_K, 0x40000000, 0, 1),
+            bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        ]);
+        for nr in DENIED_SYSCALLS {
+            f.extend([
+                bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, 0, 1),
+                bpf_stmt(BPF_RET | BPF_K, deny),
+            ]);
+        }
+        f.extend([
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone3 as u32, 0, 1),
+            bpf_stmt(
+                BPF_RET | BPF_K,
+                SECCOMP_RET_ERRNO | (libc::ENOSYS as u32 & SECCOMP_RET_DATA),
+            ),
+            // The flags are the first argument of clone, and the lower half
+            // of it comes first on little endian
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone as u32, 0, 4),
+            bpf_stmt(BPF_LD | BPF_W | BPF_ABS, ARG0),
+            bpf_stmt(BPF_ALU | BPF_AND | BPF_K, NAMESPACE_FLAGS),
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 0),
+            bpf_stmt(BPF_RET | BPF_K, deny),
+            bpf_stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+        ]);
+        Ok(SeccompFilter(f))
+    }
+
+    /// Applies the filter to the current thread, which needs
+    /// `PR_SET_NO_NEW_PRIVS` or `CAP_SYS_ADMIN`. Only allocation-free calls
+    /// are made, so this is safe to use in `pre_exec`.
+    pub fn apply(&self) -> nix::Result<()> {
+        let prog = libc::sock_fprog {
+            len: self.0.len() as libc::c_ushort,
+            filter: self.0.as_ptr() as *mut libc::sock_filter,
+        };
+        let ret = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        nix::errno::Errno::result(ret)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seccomp_filter() {
+        let filter = SeccompFilter::default_filter().unwrap();
+        // The filter and no_new_privs only apply to the thread that sets them
+        std::thread::spawn(move || {
+            nix::sys::prctl::set_no_new_privs().unwrap();
+            filter.apply().unwrap();
+            let status =
+                std::fs::read_to_string("/proc/thread-self/status").unwrap();
+            assert!(status.lines().any(|x| x == "Seccomp:\t2"));
+            assert_eq!(unsafe { libc::unshare(libc::CLONE_NEWUSER) }, -1);
+            assert_eq!(nix::errno::Errno::last(), nix::errno::Errno::EPERM);
+        })
+        .join()
+        .unwrap();
+    }
+}
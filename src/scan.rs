@@ -0,0 +1,369 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use log::warn;
+use walkdir::WalkDir;
+
+use crate::{
+    elf::{read_elf, ElfInfo},
+    pkg::{Dependency, PackageConfig},
+};
+
+/// Directories inside of a package that the dynamic linker searches
+pub const LIB_DIRS: [&str; 4] = ["lib", "lib64", "usr/lib", "usr/lib64"];
+
+/// Returns true if a path relative to the package root looks like a shared
+/// library (or dynamic linker) that other packages could link against.
+pub fn is_library_path(rel: &Path) -> bool {
+    let rel = rel.strip_prefix("./").unwrap_or(rel);
+    LIB_DIRS.iter().any(|x| rel.starts_with(x))
+        && rel
+            .file_name()
+            .is_some_and(|x| x.to_string_lossy().contains(".so"))
+}
+
+/// Gets the file names of all shared libraries shipped in a package directory
+pub fn library_names(dir: &Path) -> HashSet<String> {
+    let mut ret = HashSet::<String>::new();
+    for lib_dir in LIB_DIRS {
+        for ent in WalkDir::new(dir.join(lib_dir))
+            .into_iter()
+            .filter_map(|e| e.ok())
+        {
+            if ent.file_type().is_dir() {
+                continue;
+            }
+            if let Ok(rel) = ent.path().strip_prefix(dir) {
+                if is_library_path(rel) {
+                    ret.insert(ent.file_name().to_string_lossy().to_string());
+                }
+            }
+        }
+    }
+    ret
+}
+
+/// Reads the dynamic linking information of every ELF file in a package
+/// directory. Paths are relative to `dir`.
+pub fn scan_directory(dir: &Path) -> Result<Vec<(PathBuf, ElfInfo)>> {
+    let mut ret = Vec::<(PathBuf, ElfInfo)>::new();
+    for ent in WalkDir::new(dir).into_iter().filter_map(|e| e.ok()) {
+        if !ent.file_type().is_file() {
+            continue;
+        }
+        let rel = ent.path().strip_prefix(dir)?;
+        if rel.starts_with("dpt") {
+            continue;
+        }
+        match read_elf(ent.path()) {
+            Ok(Some(x)) => ret.push((rel.to_path_buf(), x)),
+            Ok(None) => {}
+            Err(x) => warn!("Failed to read ELF file {}: {x}", rel.display()),
+        }
+    }
+    Ok(ret)
+}
+
+/// Gets every library name that the ELF files need, including interpreters
+pub fn required_libraries(elfs: &[(PathBuf, ElfInfo)]) -> Vec<String> {
+    let mut ret = Vec::<String>::new();
+    for (_, info) in elfs {
+        let interp = info.interpreter.as_ref().and_then(|x| {
+            Path::new(x)
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+        });
+        for lib in interp.iter().chain(info.needed.iter()) {
+            if !ret.contains(lib) {
+                ret.push(lib.clone());
+            }
+        }
+    }
+    ret
+}
+
+/// Maps the libraries required by a package onto the packages that provide
+/// them. Returns the dependencies that should be added to the package, as well
+/// as the libraries that no package provides.
+pub fn suggest_dependencies(
+    cfg: &PackageConfig,
+    required: &[String],
+    own_libraries: &HashSet<String>,
+    sonames: &BTreeMap<String, Vec<String>>,
+) -> (Vec<Dependency>, Vec<String>) {
+    let mut suggested = Vec::<Dependency>::new();
+    let mut unresolved = Vec::<String>::new();
+
+    for lib in required {
+        if own_libraries.contains(lib) {
+            continue;
+        }
+        let providers = match sonames.get(lib) {
+            Some(x) if !x.is_empty() => x,
+            _ => {
+                unresolved.push(lib.clone());
+                continue;
+            }
+        };
+        let satisfied = providers.iter().any(|p| {
+            *p == cfg.name
+                || cfg.depends.iter().any(|d| d.name == *p)
+                || suggested.iter().any(|d| d.name == *p)
+        });
+        if satisfied {
+            continue;
+        }
+        if providers.len() > 1 {
+            warn!(
+                "{lib} is provided by multiple packages ({}), picking {}",
+                providers.join(", "),
+                providers[0]
+            );
+        }
+        suggested.push(Dependency {
+            name: providers[0].clone(),
+            version: String::new(),
+        });
+    }
+
+    (suggested, unresolved)
+}
+
+fn is_ident(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Splits RON text into the start, end and first byte of its tokens, where
+/// every byte outside of strings is its own token. Whitespace and comments
+/// are skipped.
+fn ron_tokens(text: &str) -> Vec<(usize, usize, u8)> {
+    let b = text.as_bytes();
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < b.len() {
+        let start = i;
+        if b[i..].starts_with(b"//") {
+            while i < b.len() && b[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        }
+        if b[i..].starts_with(b"/*") {
+            let mut nesting = 0;
+            while i < b.len() {
+                if b[i..].starts_with(b"/*") {
+                    nesting += 1;
+                    i += 2;
+                } else if b[i..].starts_with(b"*/") {
+                    nesting -= 1;
+                    i += 2;
+                    if nesting == 0 {
+                        break;
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+            continue;
+        }
+        if b[i] == b'"' || b[i] == b'\'' {
+            let quote = b[i];
+            i += 1;
+            while i < b.len() && b[i] != quote {
+                if b[i] == b'\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            ret.push((start, (i + 1).min(b.len()), b'"'));
+            i += 1;
+            continue;
+        }
+        if b[i] == b'r' && (i == 0 || !is_ident(b[i - 1])) {
+            let hashes = b[i + 1..].iter().take_while(|x| **x == b'#').count();
+            if b.get(i + 1 + hashes) == Some(&b'"') {
+                let close = [b"\"".as_slice(), &vec![b'#'; hashes]].concat();
+                let body = i + 2 + hashes;
+                let end = b[body..]
+                    .windows(close.len())
+                    .position(|x| x == close)
+                    .map_or(b.len(), |x| body + x + close.len());
+                ret.push((start, end, b'"'));
+                i = end;
+                continue;
+            }
+        }
+        if !b[i].is_ascii_whitespace() {
+            ret.push((start, i + 1, b[i]));
+        }
+        i += 1;
+    }
+    ret
+}
+
+/// Adds `deps` to the end of the `depends` list in the text of a `pkg.ron`,
+/// leaving the rest of it, like comments and formatting, as it is
+pub fn add_dependencies(text: &str, deps: &[Dependency]) -> Result<String> {
+    let tokens = ron_tokens(text);
+    let mut depth = 0;
+    let mut list = None;
+    for (k, &(start, _, c)) in tokens.iter().enumerate() {
+        match c {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth -= 1,
+            _ => {}
+        }
+        let is_field = depth == 1
+            && text[start..].starts_with("depends")
+            && !text.as_bytes().get(start + 7).is_some_and(|x| is_ident(*x))
+            && k > 0
+            && matches!(tokens[k - 1].2, b'(' | b',');
+        if !is_field {
+            continue;
+        }
+        if let (Some((_, _, b':')), Some((_, _, b'['))) =
+            (tokens.get(k + 7), tokens.get(k + 8))
+        {
+            list = Some((start, k + 8));
+            break;
+        }
+    }
+    let Some((field, open)) = list else {
+        bail!("No depends list found");
+    };
+    let mut depth = 0;
+    let Some(close) = (open..tokens.len()).find(|x| {
+        match tokens[*x].2 {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth -= 1,
+            _ => {}
+        }
+        depth == 0
+    }) else {
+        bail!("The depends list isn't closed");
+    };
+
+    let line_start = |x: usize| text[..x].rfind('\n').map_or(0, |x| x + 1);
+    let indent = &text[line_start(field)..field];
+    let indent = &indent[..indent.len() - indent.trim_start().len()];
+    let unit = if indent.starts_with('\t') {
+        "\t"
+    } else {
+        "    "
+    };
+    let (_, last_end, last) = tokens[close - 1];
+    let comma = if last == b'[' || last == b',' {
+        ""
+    } else {
+        ","
+    };
+    let mut entries = String::new();
+    for x in deps {
+        entries += &format!(
+            "{indent}{unit}(name: {}, version: {}),\n",
+            ron::to_string(&x.name)?,
+            ron::to_string(&x.version)?
+        );
+    }
+
+    let close = tokens[close].0;
+    let mut ret = text[..last_end].to_string() + comma;
+    if text[line_start(close)..close].trim().is_empty() {
+        // The list is closed on its own line, which the entries go before
+        ret += &text[last_end..line_start(close)];
+        ret += &entries;
+        ret += &text[line_start(close)..];
+    } else {
+        ret += "\n";
+        ret += &entries;
+        ret += indent;
+        ret += &text[close..];
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pkg::get_package_config;
+
+    #[test]
+    fn test_is_library_path() {
+        assert!(is_library_path(Path::new("usr/lib/libc.so.6")));
+        assert!(is_library_path(Path::new("./lib64/ld-linux-x86-64.so.2")));
+        assert!(is_library_path(Path::new("usr/lib/x86_64/libssl.so.3")));
+        assert!(!is_library_path(Path::new("usr/bin/python.so")));
+        assert!(!is_library_path(Path::new("usr/lib/libc.a")));
+    }
+
+    #[test]
+    fn suggest_dependencies_1() {
+        let cfg = PackageConfig {
+            name: "curl".to_string(),
+            version: "8.0".to_string(),
+            depends: vec![Dependency {
+                name: "glibc".to_string(),
+                version: "".to_string(),
+            }],
+            ..Default::default()
+        };
+        let sonames = BTreeMap::from([
+            ("libc.so.6".to_string(), vec!["glibc".to_string()]),
+            (
+                "libssl.so.3".to_string(),
+                vec!["openssl".to_string(), "libressl".to_string()],
+            ),
+            ("libcrypto.so.3".to_string(), vec!["openssl".to_string()]),
+        ]);
+        let required = vec![
+            "libc.so.6".to_string(),
+            "libssl.so.3".to_string(),
+            "libcrypto.so.3".to_string(),
+            "libcurl.so.4".to_string(),
+            "libz.so.1".to_string(),
+        ];
+        let own = HashSet::from(["libcurl.so.4".to_string()]);
+
+        let (suggested, unresolved) =
+            suggest_dependencies(&cfg, &required, &own, &sonames);
+        assert_eq!(
+            suggested,
+            vec![Dependency {
+                name: "openssl".to_string(),
+                version: "".to_string()
+            }]
+        );
+        assert_eq!(unresolved, vec!["libz.so.1".to_string()]);
+    }
+
+    #[test]
+    fn add_dependencies_1() {
+        let deps = [
+            Dependency {
+                name: "openssl".to_string(),
+                version: "".to_string(),
+            },
+            Dependency {
+                name: "zlib".to_string(),
+                version: ">=1.3".to_string(),
+            },
+        ];
+        let text = "(\n\tname: \"curl\",\n\tversion: \"8.0\",\n\tdescription: \"Has depends: [ in it\",\n\tdepends: [\n\t\t(name: \"glibc\", version: \"\") // libc\n\t],\n\t// Kept\n\tglue: [],\n)\n";
+        assert_eq!(
+            add_dependencies(text, &deps).unwrap(),
+            "(\n\tname: \"curl\",\n\tversion: \"8.0\",\n\tdescription: \"Has depends: [ in it\",\n\tdepends: [\n\t\t(name: \"glibc\", version: \"\"), // libc\n\t\t(name: \"openssl\", version: \"\"),\n\t\t(name: \"zlib\", version: \">=1.3\"),\n\t],\n\t// Kept\n\tglue: [],\n)\n"
+        );
+
+        let text = "(name: \"a\", version: \"1\", /* depends: */ depends: [], glue: [])";
+        let cfg = get_package_config(&add_dependencies(text, &deps).unwrap())
+            .unwrap();
+        assert_eq!(cfg.depends, deps);
+
+        assert!(
+            add_dependencies("(name: \"a\", version: \"1\")", &deps).is_err()
+        );
+    }
+}
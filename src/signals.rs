@@ -0,0 +1,211 @@
+use std::os::fd::{BorrowedFd, RawFd};
+
+use anyhow::Result;
+use nix::{
+    sys::{
+        signal::{kill, killpg, sigprocmask, SigSet, SigmaskHow, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{getpgid, getpgrp, getpid, isatty, tcgetpgrp, tcsetpgrp, Pid},
+};
+
+/// Signals that dpt passes on to programs. Signals that stop a process aren't
+/// blocked, so dpt is stopped along with the program.
+const FORWARDED_SIGNALS: [Signal; 16] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+    Signal::SIGUSR1,
+    Signal::SIGUSR2,
+    Signal::SIGWINCH,
+    Signal::SIGCONT,
+    Signal::SIGALRM,
+    Signal::SIGVTALRM,
+    Signal::SIGPROF,
+    Signal::SIGXCPU,
+    Signal::SIGXFSZ,
+    Signal::SIGPWR,
+    Signal::SIGURG,
+    Signal::SIGIO,
+];
+
+/// Signals that end dpt, which it cleans up after
+const TERMINATING_SIGNALS: [Signal; 4] = [
+    Signal::SIGHUP,
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTERM,
+];
+
+fn waited_signals() -> SigSet {
+    let mut set = SigSet::empty();
+    for sig in FORWARDED_SIGNALS {
+        set.add(sig);
+    }
+    set.add(Signal::SIGCHLD);
+    set
+}
+
+/// Blocks the signals that are waited for, so that they can be forwarded. This
+/// has to happen before the child is started. `SIGTTOU` is blocked too, so
+/// that the terminal can be handed over from the background.
+pub fn block_signals() -> Result<()> {
+    let mut set = waited_signals();
+    set.add(Signal::SIGTTOU);
+    sigprocmask(SigmaskHow::SIG_BLOCK, Some(&set), None)?;
+    Ok(())
+}
+
+/// Unblocks every signal. Only allocation-free calls are made, so this is safe
+/// to use in `pre_exec`.
+pub fn unblock_signals() -> nix::Result<()> {
+    sigprocmask(SigmaskHow::SIG_SETMASK, Some(&SigSet::empty()), None)
+}
+
+/// Runs `f` with the signals that end dpt held back, and returns the first
+/// one that arrived meanwhile. The caller can then clean up after `f`, which
+/// is done writing by then, before exiting like the signal would have.
+pub fn hold_signals<T>(f: impl FnOnce() -> T) -> Result<(T, Option<Signal>)> {
+    let mut set = SigSet::empty();
+    for sig in TERMINATING_SIGNALS {
+        set.add(sig);
+    }
+    let mut old = SigSet::empty();
+    sigprocmask(SigmaskHow::SIG_BLOCK, Some(&set), Some(&mut old))?;
+    let ret = f();
+    // Takes a pending signal without waiting for one
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let sig = unsafe {
+        libc::sigtimedwait(set.as_ref(), std::ptr::null_mut(), &timeout)
+    };
+    sigprocmask(SigmaskHow::SIG_SETMASK, Some(&old), None)?;
+    Ok((ret, Signal::try_from(sig).ok()))
+}
+
+/// Waits for one of `set`, returning it and who sent it. The sender is `None`
+/// for signals from the kernel, like the ones that a terminal sends to its
+/// foreground process group.
+fn wait_signal(set: &SigSet) -> Result<(Signal, Option<Pid>)> {
+    let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+    // Being stopped and continued interrupts the wait
+    let ret = loop {
+        let ret = unsafe { libc::sigwaitinfo(set.as_ref(), &mut info) };
+        match nix::errno::Errno::result(ret) {
+            Err(nix::errno::Errno::EINTR) => continue,
+            x => break x?,
+        }
+    };
+    let sig = Signal::try_from(ret)?;
+    let sender = if info.si_code == libc::SI_KERNEL {
+        None
+    } else {
+        Some(Pid::from_raw(unsafe { info.si_pid() }))
+    };
+    Ok((sig, sender))
+}
+
+/// Turns how a process ended into an exit code like a shell does, where a
+/// signal becomes 128 plus its number
+pub fn exit_code(status: WaitStatus) -> Option<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Some(code),
+        WaitStatus::Signaled(_, sig, _) => Some(128 + sig as i32),
+        _ => None,
+    }
+}
+
+/// Waits for `child`, which is in our process group, and returns its exit
+/// code. Other children that exit are reaped, which the init of a PID
+/// namespace has to do. With `forward`, signals that were sent to us alone are
+/// passed on to the process group. Signals from the terminal or from inside of
+/// the group already reached everyone in it.
+pub fn wait_in_group(child: Pid, forward: bool) -> Result<i32> {
+    let set = waited_signals();
+    let own = getpgrp();
+    loop {
+        let (sig, sender) = wait_signal(&set)?;
+        if sig == Signal::SIGCHLD {
+            loop {
+                match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                    Ok(WaitStatus::StillAlive) | Err(_) => break,
+                    Ok(status) if status.pid() == Some(child) => {
+                        if let Some(code) = exit_code(status) {
+                            return Ok(code);
+                        }
+                    }
+                    Ok(_) => {}
+                }
+            }
+            continue;
+        }
+        if !forward {
+            continue;
+        }
+        if let Some(sender) = sender {
+            if getpgid(Some(sender)) != Ok(own) {
+                let _ = killpg(own, sig);
+            }
+        }
+    }
+}
+
+/// Finds the terminal on stdin, stdout or stderr that our process group is in
+/// the foreground of
+pub fn foreground_tty() -> Option<RawFd> {
+    [0, 1, 2].into_iter().find(|x| {
+        let fd = unsafe { BorrowedFd::borrow_raw(*x) };
+        isatty(fd) == Ok(true) && tcgetpgrp(fd) == Ok(getpgrp())
+    })
+}
+
+/// Puts the process group `pgrp` in the foreground of `tty`
+pub fn give_terminal(tty: Option<RawFd>, pgrp: Pid) -> nix::Result<()> {
+    match tty {
+        Some(x) => tcsetpgrp(unsafe { BorrowedFd::borrow_raw(x) }, pgrp),
+        None => Ok(()),
+    }
+}
+
+/// Waits for `child`, which leads its own process group, while it has the
+/// terminal `tty`. Signals are passed on to its process group, and when it is
+/// stopped dpt takes the terminal back and stops too, so that the shell sees
+/// it. Returns the exit code of `child`.
+pub fn wait_foreground(child: Pid, tty: Option<RawFd>) -> Result<i32> {
+    let set = waited_signals();
+    let own = getpgrp();
+    let _ = give_terminal(tty, child);
+    loop {
+        let (sig, _) = wait_signal(&set)?;
+        if sig != Signal::SIGCHLD {
+            let _ = killpg(child, sig);
+            continue;
+        }
+        loop {
+            let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED;
+            match waitpid(child, Some(flags)) {
+                Ok(WaitStatus::Stopped(..)) => {
+                    let _ = give_terminal(tty, own);
+                    let _ = kill(getpid(), Signal::SIGSTOP);
+                    // Continued by the shell
+                    let _ = give_terminal(tty, child);
+                    let _ = killpg(child, Signal::SIGCONT);
+                }
+                Ok(WaitStatus::StillAlive) => break,
+                Ok(status) => {
+                    if let Some(code) = exit_code(status) {
+                        let _ = give_terminal(tty, own);
+                        return Ok(code);
+                    }
+                }
+                Err(x) => {
+                    let _ = give_terminal(tty, own);
+                    return Err(x.into());
+                }
+            }
+        }
+    }
+}
@@ -1,14 +1,42 @@
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::OnceLock;
 
 use crate::dpt_file::read_dpt_lock_file;
 use crate::pkg::{get_package_config, Package};
 use crate::repo::OnlinePackage;
-use crate::run::join_proper;
-use anyhow::{anyhow, bail, Result};
+use crate::user_profile::profile_packages;
+use anyhow::{anyhow, Result};
+
+static ROOTLESS: OnceLock<bool> = OnceLock::new();
+static INVOKING_UID: OnceLock<u32> = OnceLock::new();
+
+/// Returns true if dpt isn't running as root (or setuid root). Rootless dpt
+/// uses user namespaces and a per-user dpt directory.
+pub fn is_rootless() -> bool {
+    *ROOTLESS.get_or_init(|| uzers::get_effective_uid() != 0)
+}
+
+/// Returns the real uid that dpt was started with, which stays the same after
+/// a command switches to root
+pub fn get_invoking_uid() -> u32 {
+    *INVOKING_UID.get_or_init(uzers::get_current_uid)
+}
+
+/// Gets the per-user dpt directory used in rootless mode
+pub fn get_user_dpt_dir() -> PathBuf {
+    match std::env::var_os("XDG_DATA_HOME") {
+        Some(x) if !x.is_empty() => PathBuf::from(x).join("dpt"),
+        _ => PathBuf::from(std::env::var_os("HOME").unwrap_or("/".into()))
+            .join(".local/share/dpt"),
+    }
+}
 
 pub fn get_dpt_dir() -> PathBuf {
+    if is_rootless() {
+        return get_user_dpt_dir();
+    }
     if let Ok(x) = fs::read_to_string("/etc/dpt/dir") {
         PathBuf::from_str(&x)
             .expect("Malformed directory path in `/etc/dpt/dir`!")
@@ -58,23 +86,20 @@ pub fn get_installed_packages_without_dpt_file() -> Result<Vec<OnlinePackage>> {
         }
         let pkg_config = pkg_config.unwrap();
 
-        packages.push(OnlinePackage {
-            name: pkg_config.name,
-            version: pkg_config.version,
-            url,
-            depends: pkg_config.depends,
-        })
+        packages.push(OnlinePackage::from_package_config(pkg_config, url))
     }
     Ok(packages)
 }
 
-/// Gets a list of all packages that are installed and in the dpt configuration.
+/// Gets a list of all packages that are installed and in the dpt configuration
+/// or the profile of the user.
 pub fn get_installed_packages() -> Result<Vec<OnlinePackage>> {
-    let dpt = read_dpt_lock_file()?;
+    let mut locked = read_dpt_lock_file()?.packages;
+    locked.extend(profile_packages(get_invoking_uid()));
     Ok(get_installed_packages_without_dpt_file()?
         .iter()
         .filter(|x| {
-            dpt.packages.contains(&Package {
+            locked.contains(&Package {
                 name: x.name.clone(),
                 version: x.version.clone(),
             })
@@ -82,18 +107,3 @@ pub fn get_installed_packages() -> Result<Vec<OnlinePackage>> {
         .map(|x| x.to_owned())
         .collect::<Vec<OnlinePackage>>())
 }
-
-pub fn get_package_for_bin(
-    name: &str,
-    pkgs: &Vec<OnlinePackage>,
-) -> Result<OnlinePackage> {
-    for pkg in pkgs {
-        let p = Path::new(&pkg.url);
-        for bindir in vec!["usr/bin", "bin"] {
-            if join_proper(&p.join(bindir), Path::new(name))?.is_file() {
-                return Ok(pkg.clone());
-            }
-        }
-    }
-    bail!("No package found with binary '{name}'!");
-}
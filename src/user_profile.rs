@@ -0,0 +1,142 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bin_index::{build_bin_index, update_launchers, write_bin_index, BinIndex},
+    env::get_dpt_binary,
+    named_env::write_ron,
+    pkg::Package,
+    repo::{
+        get_available_packages_for, install_pkgs_and_dependencies,
+        newest_package_from_name, OnlinePackage,
+    },
+    store::{get_dpt_dir, get_installed_packages_without_dpt_file},
+};
+
+/// The packages of a user, from their own `dpt.ron`. The lock file of their
+/// profile has the same format, with the versions that were installed.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserDptFile {
+    pub packages: Vec<Package>,
+    /// Which package runs a binary that more than one package provides
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub providers: HashMap<String, String>,
+}
+
+/// Gets the `dpt.ron` of the user, in `$XDG_CONFIG_HOME/dpt` or
+/// `~/.config/dpt`
+pub fn get_user_dpt_file_location() -> PathBuf {
+    match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(x) if !x.is_empty() => PathBuf::from(x).join("dpt/dpt.ron"),
+        _ => PathBuf::from(std::env::var_os("HOME").unwrap_or("/".into()))
+            .join(".config/dpt/dpt.ron"),
+    }
+}
+
+/// Gets the profile of a user, which only dpt writes to
+pub fn get_profile_dir(uid: u32) -> PathBuf {
+    get_dpt_dir().join("profiles").join(uid.to_string())
+}
+
+pub fn get_profile_launcher_dir(uid: u32) -> PathBuf {
+    get_profile_dir(uid).join("bin")
+}
+
+pub fn read_user_dpt_file(path: &std::path::Path) -> Result<UserDptFile> {
+    ron::from_str(
+        &std::fs::read_to_string(path)
+            .context(anyhow!("Failed to read {}", path.display()))?,
+    )
+    .context(anyhow!("Failed to parse {}", path.display()))
+}
+
+pub fn read_profile_lock(uid: u32) -> Result<UserDptFile> {
+    Ok(ron::from_str(&std::fs::read_to_string(
+        get_profile_dir(uid).join("dpt.lock"),
+    )?)?)
+}
+
+/// The packages in the profile of a user, if they have one
+pub fn profile_packages(uid: u32) -> Vec<Package> {
+    read_profile_lock(uid)
+        .map(|x| x.packages)
+        .unwrap_or_default()
+}
+
+/// Installs the packages of `file` from the repositories and makes them the
+/// profile of `uid`, with a binary index and launchers like the system ones
+pub fn rebuild_profile(
+    uid: u32,
+    file: &UserDptFile,
+) -> Result<(UserDptFile, BinIndex)> {
+    let repo_packages = get_available_packages_for(
+        &file
+            .packages
+            .iter()
+            .map(|x| x.name.clone())
+            .collect::<Vec<String>>(),
+    )?;
+    let selected =
+        file.packages
+            .iter()
+            .map(|x| {
+                newest_package_from_name(&x.name, &repo_packages).context(
+                    anyhow!("Package {} is not found in repository!", x.name),
+                )
+            })
+            .collect::<Result<Vec<OnlinePackage>>>()?;
+    let done_list =
+        install_pkgs_and_dependencies(&selected, &repo_packages, false)?;
+
+    let mut lock = UserDptFile {
+        packages: Vec::with_capacity(done_list.len()),
+        providers: file.providers.clone(),
+    };
+    for x in done_list {
+        let pkg = x.to_package();
+        if !lock.packages.contains(&pkg) {
+            lock.packages.push(pkg);
+        }
+    }
+
+    let dir = get_profile_dir(uid);
+    std::fs::DirBuilder::new().recursive(true).create(&dir)?;
+    let bins = build_bin_index(
+        &lock.packages,
+        &get_installed_packages_without_dpt_file()?,
+    )?;
+    write_bin_index(&bins, &dir.join("bin-index.ron"))?;
+    update_launchers(&bins, &get_dpt_binary()?, &get_profile_launcher_dir(uid))
+        .context("Failed to update the launchers!")?;
+    // Last, so that the index is there for every package in it
+    write_ron(&dir.join("dpt.lock"), &lock)
+        .context("Failed to write the lock file of the profile")?;
+    Ok((lock, bins))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_dpt_file_1() {
+        let doc = r#"
+(
+    packages: [
+        (
+            name: "ripgrep",
+            version: ""
+        ),
+    ],
+)
+        "#;
+        let out: UserDptFile = ron::from_str(doc).unwrap();
+        assert_eq!(
+            out.packages,
+            vec![Package::new("ripgrep".into(), "".into())]
+        );
+        assert!(out.providers.is_empty());
+    }
+}